@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS pantries (
+  id VARCHAR(64) PRIMARY KEY,
+  contents JSONB NOT NULL DEFAULT '{}',
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS bake_history (
+  id SERIAL PRIMARY KEY,
+  pantry_id VARCHAR(64) NOT NULL REFERENCES pantries(id) ON DELETE CASCADE,
+  recipe JSONB NOT NULL,
+  cookies BIGINT NOT NULL,
+  pantry_before JSONB NOT NULL,
+  pantry_after JSONB NOT NULL,
+  baked_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS bake_history_pantry_id ON bake_history (pantry_id, id);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS pokemon_cache (
+  id BIGINT PRIMARY KEY,
+  data JSONB NOT NULL,
+  fetched_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS assets (
+  name VARCHAR(80) PRIMARY KEY,
+  sha256 CHAR(64) NOT NULL,
+  mime VARCHAR(64) NOT NULL,
+  width INTEGER NOT NULL,
+  height INTEGER NOT NULL,
+  size BIGINT NOT NULL,
+  uploaded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS assets_uploaded_at ON assets (uploaded_at, name);
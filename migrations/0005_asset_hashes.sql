@@ -0,0 +1,4 @@
+-- Perceptual hashes, NULL for assets uploaded before they were computed
+ALTER TABLE assets ADD COLUMN IF NOT EXISTS ahash BIGINT;
+ALTER TABLE assets ADD COLUMN IF NOT EXISTS dhash BIGINT;
+ALTER TABLE assets ADD COLUMN IF NOT EXISTS phash BIGINT;
@@ -0,0 +1,126 @@
+//! Refreshes the bundled Pokémon dataset from a PokeAPI dump
+//!
+//! ```sh
+//! cargo run --bin import_pokemon -- dump.json [data/pokemon.csv]
+//! ```
+//!
+//! The dump holds PokeAPI `pokemon` resources, either as a JSON array or one
+//! object per line. The bundle is rewritten in the CSV layout the server
+//! reads when `POKEMON_SOURCE` is `bundle`.
+
+use std::{collections::BTreeMap, fmt::Write, process::ExitCode};
+
+use serde::Deserialize;
+
+#[path = "../common/pokemon/stats.rs"]
+mod stats;
+
+use stats::STAT_NAMES;
+
+const DEFAULT_BUNDLE: &str = "data/pokemon.csv";
+
+#[derive(Deserialize)]
+struct Named {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct PokeType {
+    #[serde(rename = "type")]
+    kind: Named,
+}
+
+#[derive(Deserialize)]
+struct Stat {
+    base_stat: i32,
+    stat: Named,
+}
+
+/// The parts of a PokeAPI `pokemon` resource the bundle keeps
+#[derive(Deserialize)]
+struct Pokemon {
+    id: usize,
+    name: String,
+    height: i32,
+    weight: i32,
+    types: Vec<PokeType>,
+    stats: Vec<Stat>,
+}
+
+fn read_dump(dump: &str) -> Result<Vec<Pokemon>, String> {
+    if dump.trim_start().starts_with('[') {
+        return serde_json::from_str(dump).map_err(|e| e.to_string());
+    }
+
+    dump.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).map_err(|e| format!("line {}: {e}", i + 1)))
+        .collect()
+}
+
+/// Quotes a field if plain CSV can't hold it
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn write_bundle(pokemon: &[Pokemon]) -> String {
+    // Sorted by id, so refreshing the bundle gives readable diffs
+    let by_id: BTreeMap<_, _> = pokemon.iter().map(|p| (p.id, p)).collect();
+
+    let mut csv = format!("id,name,height,weight,types,{}\n", STAT_NAMES.join(","));
+    for (id, p) in by_id {
+        let types: Vec<_> = p.types.iter().map(|t| t.kind.name.as_str()).collect();
+        let _ = write!(
+            csv,
+            "{id},{},{},{},{}",
+            csv_field(&p.name),
+            p.height,
+            p.weight,
+            csv_field(&types.join(" "))
+        );
+
+        // Stats PokeAPI doesn't list stay empty
+        for name in STAT_NAMES {
+            let _ = match p.stats.iter().find(|s| s.stat.name == name) {
+                Some(stat) => write!(csv, ",{}", stat.base_stat),
+                None => write!(csv, ","),
+            };
+        }
+        csv.push('\n');
+    }
+
+    csv
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(dump_path) = args.next() else {
+        eprintln!("usage: import_pokemon <dump.json> [{DEFAULT_BUNDLE}]");
+        return ExitCode::FAILURE;
+    };
+    let bundle_path = args.next().unwrap_or(DEFAULT_BUNDLE.to_owned());
+
+    let pokemon = match std::fs::read_to_string(&dump_path)
+        .map_err(|e| e.to_string())
+        .and_then(|dump| read_dump(&dump))
+    {
+        Ok(pokemon) => pokemon,
+        Err(e) => {
+            eprintln!("can't read {dump_path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Err(e) = std::fs::write(&bundle_path, write_bundle(&pokemon)) {
+        eprintln!("can't write {bundle_path}: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    println!("wrote {} pokemon to {bundle_path}", pokemon.len());
+    ExitCode::SUCCESS
+}
@@ -9,14 +9,35 @@ use derive_more::{Display, Error};
 use serde_json::json;
 use sqlx::PgPool;
 
-#[derive(Debug, Display, Error)]
+pub mod pagination;
+pub mod pokemon;
+
+#[derive(Debug, Clone, Display, Error)]
 pub enum ServerError {
     #[display(fmt = "500 Internal Server Error")]
     InternalError,
+    #[display(fmt = "400 Bad Request: {}", _0)]
+    BadRequest(#[error(not(source))] String),
+    #[display(fmt = "413 Payload Too Large: {}", _0)]
+    PayloadTooLarge(#[error(not(source))] String),
+    #[display(fmt = "422 Unprocessable Entity: {}", _0)]
+    UnprocessableEntity(#[error(not(source))] String),
+    #[display(fmt = "401 Unauthorized: {}", _0)]
+    Unauthorized(#[error(not(source))] String),
+    #[display(fmt = "403 Forbidden: {}", _0)]
+    Forbidden(#[error(not(source))] String),
+    #[display(fmt = "404 Not Found: {}", _0)]
+    NotFound(#[error(not(source))] String),
+    #[display(fmt = "502 Bad Gateway: {}", _0)]
+    BadGateway(#[error(not(source))] String),
+    #[display(fmt = "503 Service Unavailable: {}", _0)]
+    ServiceUnavailable(#[error(not(source))] String),
+    #[display(fmt = "504 Gateway Timeout: {}", _0)]
+    GatewayTimeout(#[error(not(source))] String),
     PasswordError(PasswordErrors),
 }
 
-#[derive(Debug, Display, Error)]
+#[derive(Debug, Clone, Display, Error)]
 pub enum PasswordErrors {
     #[display(fmt = "8 chars")]
     LessEightChars,
@@ -57,6 +78,15 @@ impl error::ResponseError for ServerError {
     fn status_code(&self) -> StatusCode {
         match self {
             ServerError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServerError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServerError::UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ServerError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ServerError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ServerError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
             ServerError::PasswordError(inner) => match inner {
                 PasswordErrors::IOYOutOrder => StatusCode::NOT_ACCEPTABLE,
                 PasswordErrors::MissingSandwich => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
@@ -74,4 +104,9 @@ pub type EndpointRet = Result<HttpResponse, ServerError>;
 pub struct AppState {
     pub log: Mutex<HashMap<String, Instant>>,
     pub pool: PgPool,
+    /// HMAC key for the cookies day 7 hands out
+    pub cookie_key: Vec<u8>,
+    pub pokemon: Box<dyn pokemon::PokemonSource>,
+    /// Bearer token for managing day 11 assets, nobody can without one
+    pub assets_token: Option<String>,
 }
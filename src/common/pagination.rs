@@ -0,0 +1,190 @@
+use std::ops::Range;
+
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::{engine::general_purpose, Engine};
+use serde::Deserialize;
+
+use super::ServerError;
+
+const CURSOR_PREFIX: &str = "o:";
+
+/// Pagination query parameters, extract them with their own `web::Query`
+/// next to the endpoint's params since serde can't flatten numbers out of
+/// a query string.
+///
+/// Either `offset` or an opaque `cursor` (as handed out in the `Link` header,
+/// pass an empty one to start paging with cursors) picks the start of the
+/// page, `limit` picks its size. Without a limit everything after the start
+/// is returned.
+#[derive(Deserialize, Default)]
+pub struct PageParams {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+impl PageParams {
+    /// Where the page starts, for lists whose length isn't known up front
+    pub fn start(&self) -> Result<usize, ServerError> {
+        match (&self.cursor, self.offset) {
+            (Some(_), Some(_)) => Err(ServerError::BadRequest(
+                "use either offset or cursor, not both".to_owned(),
+            )),
+            (Some(cursor), None) => decode_cursor(cursor),
+            (None, offset) => Ok(offset.unwrap_or_default()),
+        }
+    }
+
+    /// Resolves the params against a list of `total` items
+    pub fn page(&self, total: usize) -> Result<Page, ServerError> {
+        self.page_with_stride(total, 1)
+    }
+
+    /// Same as [`PageParams::page`], but `limit` counts groups of `stride`
+    /// items instead of single items (e.g. day 5 limits the number of chunks)
+    pub fn page_with_stride(&self, total: usize, stride: usize) -> Result<Page, ServerError> {
+        let offset = self.start()?;
+
+        if offset > total {
+            return Err(ServerError::BadRequest(format!(
+                "offset {offset} is past the end of the list ({total} items)"
+            )));
+        }
+
+        let span = self.limit.map(|limit| limit.saturating_mul(stride));
+        let end = match span {
+            Some(span) => offset.saturating_add(span).min(total),
+            None => total,
+        };
+
+        Ok(Page {
+            start: offset,
+            end,
+            limit: self.limit,
+            span,
+            total,
+            cursor_mode: self.cursor.is_some(),
+        })
+    }
+}
+
+/// A resolved page over a list, see [`PageParams::page`]
+pub struct Page {
+    start: usize,
+    end: usize,
+    limit: Option<usize>,
+    span: Option<usize>,
+    pub total: usize,
+    cursor_mode: bool,
+}
+
+impl Page {
+    /// Range of items on this page
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Cursor for the page after this one, if there is one
+    pub fn next_cursor(&self) -> Option<String> {
+        self.next().map(encode_cursor)
+    }
+
+    fn next(&self) -> Option<usize> {
+        match self.span {
+            Some(span) if span > 0 && self.end < self.total => Some(self.end),
+            _ => None,
+        }
+    }
+
+    fn prev(&self) -> Option<usize> {
+        match self.span {
+            Some(span) if span > 0 && self.start > 0 => Some(self.start.saturating_sub(span)),
+            _ => None,
+        }
+    }
+
+    fn last(&self) -> Option<usize> {
+        match self.span {
+            Some(span) if span > 0 && self.total > 0 => Some((self.total - 1) / span * span),
+            _ => None,
+        }
+    }
+
+    /// Builds an RFC 8288 `Link` header value pointing at the first, previous,
+    /// next and last pages. Every other query param of the request is kept.
+    pub fn link_header(&self, req: &HttpRequest) -> String {
+        let rels = [
+            ("first", Some(0)),
+            ("prev", self.prev()),
+            ("next", self.next()),
+            ("last", self.last()),
+        ];
+
+        rels.iter()
+            .filter_map(|(rel, offset)| {
+                offset.map(|offset| format!("<{}>; rel=\"{rel}\"", self.page_url(req, offset)))
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn page_url(&self, req: &HttpRequest, offset: usize) -> String {
+        let mut query: Vec<String> = req
+            .query_string()
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !pair.is_empty() && !["offset", "limit", "cursor"].contains(&key)
+            })
+            .map(|pair| pair.to_owned())
+            .collect();
+
+        if self.cursor_mode {
+            query.push(format!("cursor={}", encode_cursor(offset)));
+        } else {
+            query.push(format!("offset={offset}"));
+        }
+
+        if let Some(limit) = self.limit {
+            query.push(format!("limit={limit}"));
+        }
+
+        format!("{}?{}", req.path(), query.join("&"))
+    }
+
+    /// Starts a 200 response with the `X-Total-Count` and `Link` headers set
+    pub fn respond(&self, req: &HttpRequest) -> HttpResponseBuilder {
+        let mut builder = HttpResponse::Ok();
+        builder.insert_header(("X-Total-Count", self.total.to_string()));
+
+        let links = self.link_header(req);
+        if !links.is_empty() {
+            builder.insert_header(("Link", links));
+        }
+
+        builder
+    }
+}
+
+fn encode_cursor(offset: usize) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(format!("{CURSOR_PREFIX}{offset}"))
+}
+
+fn decode_cursor(cursor: &str) -> Result<usize, ServerError> {
+    // An empty cursor asks for the first page in cursor mode
+    if cursor.is_empty() {
+        return Ok(0);
+    }
+
+    let invalid = || ServerError::BadRequest("invalid cursor".to_owned());
+
+    let decoded = general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+
+    match decoded.strip_prefix(CURSOR_PREFIX) {
+        Some(offset) => offset.parse().map_err(|_| invalid()),
+        None => Err(invalid()),
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::BTreeMap, convert::Infallible, fmt, num::NonZeroUsize, str::FromStr,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize};
+use shuttle_runtime::SecretStore;
+use sqlx::PgPool;
+
+use super::ServerError;
+
+mod bundle;
+mod cache;
+#[cfg(test)]
+mod mock;
+mod resilience;
+mod stats;
+
+pub use bundle::BundledPokemonSource;
+pub use cache::{CacheStats, CachedPokemonSource};
+pub use resilience::ResilientPokemonSource;
+pub use stats::STAT_NAMES;
+
+const DEFAULT_BASE_URL: &str = "https://pokeapi.co/api/v2";
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+/// Only a sample of 35 Pokémon, `import_pokemon` writes the full one from a
+/// PokeAPI dump
+const DEFAULT_BUNDLE: &str = "data/pokemon.csv";
+const DEFAULT_CACHE_SIZE: usize = 1024;
+const DEFAULT_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_DELAY_MS: u64 = 100;
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 30;
+
+/// A Pokémon by PokeAPI id or by name, names are kept lowercase so they match
+/// case-insensitively
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum PokeRef {
+    Id(usize),
+    Name(String),
+}
+
+impl PokeRef {
+    pub fn not_found(&self) -> ServerError {
+        ServerError::NotFound(match self {
+            PokeRef::Id(id) => format!("no pokemon with id {id}"),
+            PokeRef::Name(name) => format!("no pokemon named {name}"),
+        })
+    }
+}
+
+impl FromStr for PokeRef {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        Ok(match s.parse() {
+            Ok(id) => PokeRef::Id(id),
+            Err(_) => PokeRef::Name(s.to_lowercase()),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for PokeRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged, expecting = "a pokemon id or name")]
+        enum Raw {
+            Id(usize),
+            Name(String),
+        }
+
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Id(id) => PokeRef::Id(id),
+            Raw::Name(name) => name.parse().unwrap_or_else(|e| match e {}),
+        })
+    }
+}
+
+impl fmt::Display for PokeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PokeRef::Id(id) => write!(f, "{id}"),
+            PokeRef::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// The parts of a PokeAPI `pokemon` resource we use
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PokeData {
+    #[serde(default)]
+    pub id: usize,
+    #[serde(default)]
+    pub name: String,
+    /// In decimetres
+    #[serde(default)]
+    pub height: i32,
+    /// In hectograms
+    pub weight: i32,
+    #[serde(default, deserialize_with = "type_names")]
+    pub types: Vec<String>,
+    /// Base stats by name
+    #[serde(default, deserialize_with = "base_stats")]
+    pub stats: BTreeMap<String, i32>,
+}
+
+/// PokeAPI nests each type as `{"slot": 1, "type": {"name": "grass", ..}}`,
+/// bundles and our own cache just list the names
+fn type_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    struct Named {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PokeType {
+        Name(String),
+        Slot {
+            #[serde(rename = "type")]
+            kind: Named,
+        },
+    }
+
+    let types = Vec::<PokeType>::deserialize(deserializer)?;
+    Ok(types
+        .into_iter()
+        .map(|t| match t {
+            PokeType::Name(name) => name,
+            PokeType::Slot { kind } => kind.name,
+        })
+        .collect())
+}
+
+/// PokeAPI lists stats as `{"base_stat": 45, "stat": {"name": "hp", ..}, ..}`,
+/// our own cache keeps them as a map of names to values
+fn base_stats<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, i32>, D::Error> {
+    #[derive(Deserialize)]
+    struct Named {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Stat {
+        base_stat: i32,
+        stat: Named,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stats {
+        Map(BTreeMap<String, i32>),
+        List(Vec<Stat>),
+    }
+
+    Ok(match Stats::deserialize(deserializer)? {
+        Stats::Map(map) => map,
+        Stats::List(list) => list
+            .into_iter()
+            .map(|s| (s.stat.name, s.base_stat))
+            .collect(),
+    })
+}
+
+/// Where day 8 gets its Pokémon from
+#[async_trait]
+pub trait PokemonSource: Send + Sync {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError>;
+
+    /// Counters of the cache in front of the source, if there is one
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+/// Talks to PokeAPI (or a mirror of it) over HTTP
+pub struct HttpPokemonSource {
+    client: Client,
+    base_url: String,
+}
+
+impl HttpPokemonSource {
+    pub fn new(base_url: &str, timeout: Duration) -> Result<Self, reqwest::Error> {
+        // One client for every request, so connections get reused
+        let client = Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .build()?;
+
+        Ok(HttpPokemonSource {
+            client,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+fn upstream_error(e: reqwest::Error) -> ServerError {
+    if e.is_timeout() {
+        ServerError::GatewayTimeout("PokeAPI took too long to answer".to_owned())
+    } else {
+        ServerError::BadGateway(format!("PokeAPI request failed: {e}"))
+    }
+}
+
+#[async_trait]
+impl PokemonSource for HttpPokemonSource {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        // PokeAPI names are plain slugs, anything else can't exist and
+        // shouldn't end up in the URL
+        if let PokeRef::Name(name) = key {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                return Err(key.not_found());
+            }
+        }
+
+        let res = self
+            .client
+            .get(format!("{}/pokemon/{key}/", self.base_url))
+            .send()
+            .await
+            .map_err(upstream_error)?;
+
+        match res.status() {
+            StatusCode::NOT_FOUND => Err(key.not_found()),
+            status if !status.is_success() => Err(ServerError::BadGateway(format!(
+                "PokeAPI answered with {status}"
+            ))),
+            _ => res.json::<PokeData>().await.map_err(upstream_error),
+        }
+    }
+}
+
+fn secret_number<T: FromStr>(secrets: &SecretStore, key: &str, default: T) -> Result<T, String> {
+    match secrets.get(key) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("{key} is not a number: {value}")),
+        None => Ok(default),
+    }
+}
+
+/// Picks the Pokémon source from the secrets
+///
+/// * `POKEMON_SOURCE` - `pokeapi` (the default) or `bundle` to skip the network
+///   entirely and serve the dataset in `POKEMON_BUNDLE`
+/// * `POKEMON_BUNDLE` - path of the bundled dataset, CSV or JSON. The one in
+///   the repo is partial, anything not in it is a 404
+/// * `POKEAPI_URL` - base URL of PokeAPI or a mirror of it
+/// * `POKEAPI_TIMEOUT_SECS` - request timeout
+/// * `POKEAPI_RETRIES` - extra attempts after a failed request
+/// * `POKEAPI_RETRY_DELAY_MS` - wait before the first retry, doubled for each
+///   one after it
+/// * `POKEAPI_BREAKER_THRESHOLD` - failures in a row before PokeAPI gets a
+///   break, 0 never stops asking it
+/// * `POKEAPI_BREAKER_COOLDOWN_SECS` - how long that break lasts
+/// * `POKEMON_CACHE_SIZE` - how many Pokémon to keep in memory, 0 disables caching
+/// * `POKEMON_CACHE_TTL_SECS` - how long a cached Pokémon stays valid
+/// * `POKEMON_CACHE_PERSIST` - `true` to also keep them in the database
+pub fn source_from_secrets(
+    secrets: &SecretStore,
+    pool: &PgPool,
+) -> Result<Box<dyn PokemonSource>, String> {
+    match secrets.get("POKEMON_SOURCE").as_deref() {
+        Some("pokeapi") | None => {}
+        Some("bundle") => {
+            let path = secrets
+                .get("POKEMON_BUNDLE")
+                .unwrap_or(DEFAULT_BUNDLE.to_owned());
+            return Ok(Box::new(BundledPokemonSource::from_file(&path)?));
+        }
+        Some(other) => return Err(format!("unknown POKEMON_SOURCE: {other}")),
+    }
+
+    let base_url = secrets
+        .get("POKEAPI_URL")
+        .unwrap_or(DEFAULT_BASE_URL.to_owned());
+    let timeout = secret_number(secrets, "POKEAPI_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS)?;
+    let source = HttpPokemonSource::new(&base_url, Duration::from_secs(timeout))
+        .map_err(|e| e.to_string())?;
+    let source = ResilientPokemonSource::new(
+        Box::new(source),
+        secret_number(secrets, "POKEAPI_RETRIES", DEFAULT_RETRIES)?,
+        Duration::from_millis(secret_number(
+            secrets,
+            "POKEAPI_RETRY_DELAY_MS",
+            DEFAULT_RETRY_DELAY_MS,
+        )?),
+        secret_number(
+            secrets,
+            "POKEAPI_BREAKER_THRESHOLD",
+            DEFAULT_BREAKER_THRESHOLD,
+        )?,
+        Duration::from_secs(secret_number(
+            secrets,
+            "POKEAPI_BREAKER_COOLDOWN_SECS",
+            DEFAULT_BREAKER_COOLDOWN_SECS,
+        )?),
+    );
+
+    let Some(capacity) = NonZeroUsize::new(secret_number(
+        secrets,
+        "POKEMON_CACHE_SIZE",
+        DEFAULT_CACHE_SIZE,
+    )?) else {
+        return Ok(Box::new(source));
+    };
+    let ttl = secret_number(secrets, "POKEMON_CACHE_TTL_SECS", DEFAULT_CACHE_TTL_SECS)?;
+    let store = match secrets.get("POKEMON_CACHE_PERSIST").as_deref() {
+        Some("true") => Some(pool.clone()),
+        Some("false") | None => None,
+        Some(other) => return Err(format!("POKEMON_CACHE_PERSIST is not a boolean: {other}")),
+    };
+
+    Ok(Box::new(CachedPokemonSource::new(
+        Box::new(source),
+        capacity,
+        Duration::from_secs(ttl),
+        store,
+    )))
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use async_trait::async_trait;
+use csv_core::{ReadFieldResult, Reader as CsvReader};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{PokeData, PokeRef, PokemonSource, STAT_NAMES};
+use crate::common::ServerError;
+
+/// One entry of a JSON list bundle
+#[derive(Deserialize)]
+struct BundledPokemon {
+    id: usize,
+    #[serde(flatten)]
+    data: PokeData,
+}
+
+/// Splits a whole CSV file into rows of fields, skipping blank lines
+fn csv_rows(mut input: &[u8]) -> Result<Vec<Vec<String>>, String> {
+    let mut reader = CsvReader::new();
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = vec![0; 64];
+    let mut len = 0;
+
+    loop {
+        let (res, nin, nout) = reader.read_field(input, &mut field[len..]);
+        input = &input[nin..];
+        len += nout;
+
+        match res {
+            // Running out of input once more tells the reader the file ended
+            ReadFieldResult::InputEmpty => {}
+            ReadFieldResult::OutputFull => field.resize(field.len() * 2, 0),
+            ReadFieldResult::Field { record_end } => {
+                let value = std::str::from_utf8(&field[..len]).map_err(|_| "not valid UTF-8")?;
+                row.push(value.trim().to_owned());
+                len = 0;
+
+                if record_end {
+                    if row.iter().any(|field| !field.is_empty()) {
+                        rows.push(row);
+                    }
+                    row = Vec::new();
+                }
+            }
+            ReadFieldResult::End => return Ok(rows),
+        }
+    }
+}
+
+/// Reads a CSV bundle with an `id,name,height,weight,types` header followed by
+/// a column for each base stat, where `types` is space separated. Only `id`
+/// and `weight` are required.
+fn parse_csv(input: &[u8]) -> Result<HashMap<usize, PokeData>, String> {
+    let mut rows = csv_rows(input)?.into_iter();
+    let header = rows.next().ok_or("missing the header row")?;
+    let column = |name: &str| header.iter().position(|h| h.eq_ignore_ascii_case(name));
+
+    let id_column = column("id").ok_or("missing the id column")?;
+    let weight_column = column("weight").ok_or("missing the weight column")?;
+    let (name_column, height_column, types_column) =
+        (column("name"), column("height"), column("types"));
+    let stat_columns: Vec<_> = STAT_NAMES
+        .iter()
+        .filter_map(|stat| Some((*stat, column(stat)?)))
+        .collect();
+
+    let mut pokemon = HashMap::new();
+    for (line, row) in rows.enumerate() {
+        // Counting the header and starting at one, like editors do
+        let line = line + 2;
+        let get = |column: Option<usize>| column.and_then(|c| row.get(c)).map(String::as_str);
+        let number = |column: Option<usize>, name: &str| match get(column) {
+            None | Some("") => Ok(0),
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("row {line}: {name} is not a number: {value}")),
+        };
+
+        let mut stats = BTreeMap::new();
+        for &(stat, column) in &stat_columns {
+            if get(Some(column)).is_some_and(|value| !value.is_empty()) {
+                stats.insert(stat.to_owned(), number(Some(column), stat)?);
+            }
+        }
+
+        let id = get(Some(id_column))
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| format!("row {line}: missing or invalid id"))?;
+        let data = PokeData {
+            id,
+            name: get(name_column).unwrap_or_default().to_owned(),
+            height: number(height_column, "height")?,
+            weight: number(Some(weight_column), "weight")?,
+            types: get(types_column)
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+            stats,
+        };
+
+        if pokemon.insert(id, data).is_some() {
+            return Err(format!("row {line}: id {id} is listed twice"));
+        }
+    }
+
+    Ok(pokemon)
+}
+
+/// Reads a JSON bundle, either a list of entries with an `id` or an object
+/// keyed by id like `{"25": {"weight": 60}}`
+fn parse_json(input: &[u8]) -> Result<HashMap<usize, PokeData>, String> {
+    let bundle: Value = serde_json::from_slice(input).map_err(|e| e.to_string())?;
+
+    if bundle.is_array() {
+        let list = Vec::<BundledPokemon>::deserialize(bundle).map_err(|e| e.to_string())?;
+        Ok(list
+            .into_iter()
+            .map(|entry| (entry.id, entry.data))
+            .collect())
+    } else {
+        HashMap::deserialize(bundle).map_err(|e| e.to_string())
+    }
+}
+
+/// Serves Pokémon from a dataset file shipped with the app, so day 8 works
+/// without network access. The format follows the extension: `.csv` for the
+/// layout `import_pokemon` writes, JSON otherwise.
+pub struct BundledPokemonSource {
+    pokemon: HashMap<usize, PokeData>,
+    /// Lowercase names to ids
+    names: HashMap<String, usize>,
+}
+
+impl BundledPokemonSource {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let file = std::fs::read(path).map_err(|e| format!("can't read {path}: {e}"))?;
+
+        let is_csv = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        let mut pokemon = if is_csv {
+            parse_csv(&file)
+        } else {
+            parse_json(&file)
+        }
+        .map_err(|e| format!("invalid {path}: {e}"))?;
+
+        // JSON bundles may only have the ids as keys
+        for (id, data) in pokemon.iter_mut() {
+            data.id = *id;
+        }
+
+        let names = pokemon
+            .values()
+            .filter(|data| !data.name.is_empty())
+            .map(|data| (data.name.to_lowercase(), data.id))
+            .collect();
+
+        Ok(BundledPokemonSource { pokemon, names })
+    }
+}
+
+#[async_trait]
+impl PokemonSource for BundledPokemonSource {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        let id = match key {
+            PokeRef::Id(id) => Some(id),
+            PokeRef::Name(name) => self.names.get(name),
+        };
+
+        // Bundles are often partial, so say where the answer came from
+        id.and_then(|id| self.pokemon.get(id))
+            .cloned()
+            .ok_or_else(|| {
+                ServerError::NotFound(format!(
+                    "{key} is not in the bundled dataset, it only has {} pokemon",
+                    self.pokemon.len()
+                ))
+            })
+    }
+}
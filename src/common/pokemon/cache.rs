@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::Serialize;
+use sqlx::{types::Json, PgPool};
+use tokio::sync::OnceCell;
+
+use super::{PokeData, PokeRef, PokemonSource};
+use crate::common::ServerError;
+
+/// A lookup in progress, shared by every request asking for the same Pokémon
+type Flight = Arc<OnceCell<Result<PokeData, ServerError>>>;
+
+struct Entry {
+    data: PokeData,
+    fetched: Instant,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CacheStats {
+    /// Answered from memory
+    pub hits: u64,
+    /// Answered from the database after a restart or eviction
+    pub stored_hits: u64,
+    /// Went all the way to the wrapped source
+    pub misses: u64,
+    /// Waited on a lookup another request had already started
+    pub coalesced: u64,
+    pub entries: usize,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub persistent: bool,
+}
+
+/// Keeps recent answers of another source in an LRU, optionally backed by the
+/// `pokemon_cache` table so they survive restarts
+pub struct CachedPokemonSource {
+    inner: Box<dyn PokemonSource>,
+    ttl: Duration,
+    memory: Mutex<LruCache<usize, Entry>>,
+    /// Ids of the names looked up so far
+    names: Mutex<HashMap<String, usize>>,
+    in_flight: Mutex<HashMap<PokeRef, Flight>>,
+    store: Option<PgPool>,
+    hits: AtomicU64,
+    stored_hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl CachedPokemonSource {
+    pub fn new(
+        inner: Box<dyn PokemonSource>,
+        capacity: NonZeroUsize,
+        ttl: Duration,
+        store: Option<PgPool>,
+    ) -> Self {
+        CachedPokemonSource {
+            inner,
+            ttl,
+            memory: Mutex::new(LruCache::new(capacity)),
+            names: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            store,
+            hits: AtomicU64::new(0),
+            stored_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    fn id_of(&self, key: &PokeRef) -> Option<usize> {
+        match key {
+            PokeRef::Id(id) => Some(*id),
+            PokeRef::Name(name) => self.names.lock().unwrap().get(name).copied(),
+        }
+    }
+
+    fn recall(&self, key: &PokeRef) -> Option<PokeData> {
+        let id = self.id_of(key)?;
+        let mut memory = self.memory.lock().unwrap();
+
+        match memory.get(&id) {
+            Some(entry) if entry.fetched.elapsed() < self.ttl => Some(entry.data.clone()),
+            Some(_) => {
+                memory.pop(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// `age` is how long ago the data came from the wrapped source, so entries
+    /// loaded from the database don't get a fresh TTL
+    fn remember(&self, data: PokeData, age: Duration) {
+        let now = Instant::now();
+        let fetched = now.checked_sub(age).unwrap_or(now);
+
+        // Names stay known after an eviction, they don't change ids
+        if !data.name.is_empty() {
+            let name = data.name.to_lowercase();
+            self.names.lock().unwrap().insert(name, data.id);
+        }
+
+        self.memory
+            .lock()
+            .unwrap()
+            .put(data.id, Entry { data, fetched });
+    }
+
+    async fn load(&self, key: &PokeRef) -> Option<(PokeData, Duration)> {
+        let pool = self.store.as_ref()?;
+        let query = match key {
+            PokeRef::Id(id) => sqlx::query_as(
+                "SELECT id, data, EXTRACT(EPOCH FROM now() - fetched_at)::FLOAT8 FROM pokemon_cache
+                WHERE id = $1 AND fetched_at > now() - make_interval(secs => $2);",
+            )
+            .bind(i64::try_from(*id).ok()?),
+            PokeRef::Name(name) => sqlx::query_as(
+                "SELECT id, data, EXTRACT(EPOCH FROM now() - fetched_at)::FLOAT8 FROM pokemon_cache
+                WHERE lower(data->>'name') = $1 AND fetched_at > now() - make_interval(secs => $2);",
+            )
+            .bind(name),
+        };
+
+        // A broken store only costs us the persistence, lookups still work
+        let row: Option<(i64, Json<PokeData>, f64)> = query
+            .bind(self.ttl.as_secs_f64())
+            .fetch_optional(pool)
+            .await
+            .ok()?;
+
+        // Rows written before the id was part of the data don't have it
+        let (id, Json(mut data), age) = row?;
+        data.id = usize::try_from(id).ok()?;
+
+        Some((data, Duration::from_secs_f64(age.max(0.0))))
+    }
+
+    async fn save(&self, data: &PokeData) {
+        let (Some(pool), Ok(id)) = (&self.store, i64::try_from(data.id)) else {
+            return;
+        };
+
+        let _ = sqlx::query(
+            "INSERT INTO pokemon_cache (id, data) VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, fetched_at = now();",
+        )
+        .bind(id)
+        .bind(Json(data))
+        .execute(pool)
+        .await;
+    }
+
+    async fn fetch(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        if let Some((data, age)) = self.load(key).await {
+            self.stored_hits.fetch_add(1, Ordering::Relaxed);
+            self.remember(data.clone(), age);
+            return Ok(data);
+        }
+
+        // Errors aren't cached, the next request tries again
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let mut data = self.inner.pokemon(key).await?;
+        if let PokeRef::Id(id) = key {
+            data.id = *id;
+        }
+        self.remember(data.clone(), Duration::ZERO);
+        self.save(&data).await;
+
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl PokemonSource for CachedPokemonSource {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        if let Some(data) = self.recall(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+
+        let flight = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        // If the request doing the lookup goes away, one of the waiters takes over
+        let mut leader = false;
+        let result = flight
+            .get_or_init(|| {
+                leader = true;
+                self.fetch(key)
+            })
+            .await
+            .clone();
+
+        if leader {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight.get(key).is_some_and(|f| Arc::ptr_eq(f, &flight)) {
+                in_flight.remove(key);
+            }
+        } else {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        let memory = self.memory.lock().unwrap();
+
+        Some(CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            stored_hits: self.stored_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            entries: memory.len(),
+            capacity: memory.cap().get(),
+            ttl_secs: self.ttl.as_secs(),
+            persistent: self.store.is_some(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::mock::*, *};
+
+    fn cache(
+        source: &Arc<MockPokemonSource>,
+        capacity: usize,
+        ttl: Duration,
+    ) -> CachedPokemonSource {
+        CachedPokemonSource::new(
+            Box::new(source.clone()),
+            NonZeroUsize::new(capacity).unwrap(),
+            ttl,
+            None,
+        )
+    }
+
+    fn name(name: &str) -> PokeRef {
+        PokeRef::Name(name.to_owned())
+    }
+
+    #[actix_web::test]
+    async fn names_are_recalled_by_id_and_ids_by_name() {
+        let source = Arc::new(MockPokemonSource::new(vec![
+            pokemon(1, "bulbasaur"),
+            pokemon(4, "charmander"),
+        ]));
+        let cache = cache(&source, 8, Duration::from_secs(60));
+
+        cache.pokemon(&name("bulbasaur")).await.unwrap();
+        assert_eq!(
+            cache.pokemon(&PokeRef::Id(1)).await.unwrap().name,
+            "bulbasaur"
+        );
+        cache.pokemon(&PokeRef::Id(4)).await.unwrap();
+        assert_eq!(cache.pokemon(&name("charmander")).await.unwrap().id, 4);
+
+        assert_eq!(source.calls(), 2);
+        let stats = cache.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 2, 2));
+    }
+
+    #[actix_web::test]
+    async fn errors_and_evictions_go_back_to_the_source() {
+        let source = Arc::new(MockPokemonSource::new(vec![
+            pokemon(1, "bulbasaur"),
+            pokemon(4, "charmander"),
+        ]));
+        let cache = cache(&source, 1, Duration::from_secs(60));
+
+        assert!(cache.pokemon(&name("missingno")).await.is_err());
+        assert!(cache.pokemon(&name("missingno")).await.is_err());
+        cache.pokemon(&name("bulbasaur")).await.unwrap();
+        cache.pokemon(&name("charmander")).await.unwrap();
+        // Known by name, but no longer in memory
+        cache.pokemon(&name("bulbasaur")).await.unwrap();
+
+        assert_eq!(source.calls(), 5);
+        assert_eq!(cache.cache_stats().unwrap().hits, 0);
+    }
+
+    #[actix_web::test]
+    async fn entries_expire() {
+        let source = Arc::new(MockPokemonSource::new(vec![pokemon(1, "bulbasaur")]));
+        let cache = cache(&source, 8, Duration::from_millis(20));
+
+        cache.pokemon(&PokeRef::Id(1)).await.unwrap();
+        cache.pokemon(&PokeRef::Id(1)).await.unwrap();
+        assert_eq!(source.calls(), 1);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        cache.pokemon(&PokeRef::Id(1)).await.unwrap();
+        assert_eq!(source.calls(), 2);
+        assert_eq!(cache.cache_stats().unwrap().entries, 1);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_lookups_share_one_fetch() {
+        let source = Arc::new(
+            MockPokemonSource::new(vec![pokemon(1, "bulbasaur")]).slow(Duration::from_millis(20)),
+        );
+        let cache = cache(&source, 8, Duration::from_secs(60));
+
+        let (a, b) = futures_util::join!(
+            cache.pokemon(&PokeRef::Id(1)),
+            cache.pokemon(&PokeRef::Id(1))
+        );
+        assert_eq!(a.unwrap().name, b.unwrap().name);
+
+        assert_eq!(source.calls(), 1);
+        assert_eq!(cache.cache_stats().unwrap().coalesced, 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn a_waiter_takes_over_from_a_dropped_leader() {
+        let source = Arc::new(
+            MockPokemonSource::new(vec![pokemon(1, "bulbasaur")]).slow(Duration::from_millis(20)),
+        );
+        let cache = cache(&source, 8, Duration::from_secs(60));
+
+        let (leader, waiter) = futures_util::join!(
+            tokio::time::timeout(Duration::from_millis(5), cache.pokemon(&PokeRef::Id(1))),
+            cache.pokemon(&PokeRef::Id(1))
+        );
+        assert!(leader.is_err());
+        assert_eq!(waiter.unwrap().name, "bulbasaur");
+
+        assert_eq!(source.calls(), 2);
+        // The waiter ended up leading, and cleaned up after itself
+        assert_eq!(cache.cache_stats().unwrap().coalesced, 0);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+}
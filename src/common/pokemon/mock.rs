@@ -0,0 +1,91 @@
+//! An in-memory source, for testing what gets wrapped around the real ones
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+use super::{PokeData, PokeRef, PokemonSource};
+use crate::common::ServerError;
+
+pub fn pokemon(id: usize, name: &str) -> PokeData {
+    PokeData {
+        id,
+        name: name.to_owned(),
+        height: 7,
+        weight: 69,
+        types: vec!["grass".to_owned()],
+        stats: BTreeMap::new(),
+    }
+}
+
+pub struct MockPokemonSource {
+    pokemon: Vec<PokeData>,
+    /// Answered before anything is looked up, one per call
+    failures: Mutex<VecDeque<ServerError>>,
+    /// How long every answer takes
+    delay: Duration,
+    calls: AtomicUsize,
+}
+
+impl MockPokemonSource {
+    pub fn new(pokemon: Vec<PokeData>) -> Self {
+        MockPokemonSource {
+            pokemon,
+            failures: Mutex::new(VecDeque::new()),
+            delay: Duration::ZERO,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn failing(mut self, failures: impl IntoIterator<Item = ServerError>) -> Self {
+        self.failures = Mutex::new(failures.into_iter().collect());
+        self
+    }
+
+    pub fn slow(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+/// Lets a test keep an eye on the calls once the source is boxed up
+#[async_trait]
+impl PokemonSource for Arc<MockPokemonSource> {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        self.as_ref().pokemon(key).await
+    }
+}
+
+#[async_trait]
+impl PokemonSource for MockPokemonSource {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        if let Some(e) = self.failures.lock().unwrap().pop_front() {
+            return Err(e);
+        }
+
+        self.pokemon
+            .iter()
+            .find(|p| match key {
+                PokeRef::Id(id) => p.id == *id,
+                PokeRef::Name(name) => p.name == *name,
+            })
+            .cloned()
+            .ok_or_else(|| key.not_found())
+    }
+}
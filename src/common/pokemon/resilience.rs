@@ -0,0 +1,230 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use super::{PokeData, PokeRef, PokemonSource};
+use crate::common::ServerError;
+
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    /// Everything fails fast until the cooldown is over
+    Open {
+        until: Instant,
+    },
+    /// One request gets to find out if the upstream is back
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// Retries transient failures of another source with exponential backoff, and
+/// stops asking it for a while once it keeps failing
+pub struct ResilientPokemonSource {
+    inner: Box<dyn PokemonSource>,
+    retries: u32,
+    retry_delay: Duration,
+    threshold: u32,
+    cooldown: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+/// Whether asking again might help, a 404 stays a 404
+fn is_transient(e: &ServerError) -> bool {
+    matches!(
+        e,
+        ServerError::BadGateway(_) | ServerError::GatewayTimeout(_)
+    )
+}
+
+impl ResilientPokemonSource {
+    pub fn new(
+        inner: Box<dyn PokemonSource>,
+        retries: u32,
+        retry_delay: Duration,
+        threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        ResilientPokemonSource {
+            inner,
+            retries,
+            retry_delay,
+            threshold,
+            cooldown,
+            circuit: Mutex::new(Circuit::Closed { failures: 0 }),
+        }
+    }
+
+    fn admit(&self) -> Result<(), ServerError> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let now = Instant::now();
+
+        match *circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } if now < until => Err(self.unavailable()),
+            // The trial request may have been dropped, so it only holds the
+            // others off for one cooldown
+            Circuit::HalfOpen { since } if now < since + self.cooldown => Err(self.unavailable()),
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => {
+                *circuit = Circuit::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    fn record(&self, result: &Result<PokeData, ServerError>) {
+        let mut circuit = self.circuit.lock().unwrap();
+
+        match result {
+            Err(e) if is_transient(e) => {
+                let failures = match *circuit {
+                    Circuit::Closed { failures } => failures + 1,
+                    // A failed trial opens the circuit again straight away
+                    _ => self.threshold,
+                };
+
+                *circuit = if self.threshold > 0 && failures >= self.threshold {
+                    Circuit::Open {
+                        until: Instant::now() + self.cooldown,
+                    }
+                } else {
+                    Circuit::Closed { failures }
+                };
+            }
+            _ => *circuit = Circuit::Closed { failures: 0 },
+        }
+    }
+
+    fn unavailable(&self) -> ServerError {
+        ServerError::ServiceUnavailable(
+            "PokeAPI keeps failing, not asking it again for now".to_owned(),
+        )
+    }
+}
+
+#[async_trait]
+impl PokemonSource for ResilientPokemonSource {
+    async fn pokemon(&self, key: &PokeRef) -> Result<PokeData, ServerError> {
+        self.admit()?;
+
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        let result = loop {
+            match self.inner.pokemon(key).await {
+                Err(e) if is_transient(&e) && attempt < self.retries => {
+                    // Up to half of the delay again, so retries of a batch
+                    // don't all hit the upstream at the same moment
+                    let jitter = delay.mul_f64(rand::random::<f64>() / 2.0);
+                    tokio::time::sleep(delay + jitter).await;
+
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+
+        self.record(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{super::mock::*, *};
+
+    fn resilient(
+        source: &Arc<MockPokemonSource>,
+        retries: u32,
+        threshold: u32,
+    ) -> ResilientPokemonSource {
+        ResilientPokemonSource::new(
+            Box::new(source.clone()),
+            retries,
+            Duration::from_millis(1),
+            threshold,
+            Duration::from_millis(20),
+        )
+    }
+
+    fn bad_gateway() -> ServerError {
+        ServerError::BadGateway("down".to_owned())
+    }
+
+    fn circuit(source: &ResilientPokemonSource) -> &'static str {
+        match *source.circuit.lock().unwrap() {
+            Circuit::Closed { .. } => "closed",
+            Circuit::Open { .. } => "open",
+            Circuit::HalfOpen { .. } => "half-open",
+        }
+    }
+
+    #[actix_web::test]
+    async fn transient_failures_are_retried() {
+        let source = Arc::new(
+            MockPokemonSource::new(vec![pokemon(1, "bulbasaur")])
+                .failing([bad_gateway(), bad_gateway()]),
+        );
+        let resilient = resilient(&source, 2, 5);
+
+        assert!(resilient.pokemon(&PokeRef::Id(1)).await.is_ok());
+        assert_eq!(source.calls(), 3);
+        // Not found is an answer, not a failure
+        assert!(matches!(
+            resilient.pokemon(&PokeRef::Id(2)).await,
+            Err(ServerError::NotFound(_))
+        ));
+        assert_eq!(source.calls(), 4);
+        assert_eq!(circuit(&resilient), "closed");
+    }
+
+    #[actix_web::test]
+    async fn the_circuit_opens_and_closes_again() {
+        let source = Arc::new(
+            MockPokemonSource::new(vec![pokemon(1, "bulbasaur")]).failing([
+                bad_gateway(),
+                bad_gateway(),
+                bad_gateway(),
+            ]),
+        );
+        let resilient = resilient(&source, 0, 2);
+        let key = PokeRef::Id(1);
+
+        assert!(resilient.pokemon(&key).await.is_err());
+        assert_eq!(circuit(&resilient), "closed");
+        assert!(resilient.pokemon(&key).await.is_err());
+        assert_eq!(circuit(&resilient), "open");
+
+        // Fails fast, the source isn't asked
+        assert!(matches!(
+            resilient.pokemon(&key).await,
+            Err(ServerError::ServiceUnavailable(_))
+        ));
+        assert_eq!(source.calls(), 2);
+
+        // One trial after the cooldown, the others are still held off
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(resilient.admit().is_ok());
+        assert_eq!(circuit(&resilient), "half-open");
+        assert!(resilient.admit().is_err());
+
+        // A failed trial opens it again straight away
+        resilient.record(&Err(bad_gateway()));
+        assert_eq!(circuit(&resilient), "open");
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(resilient.pokemon(&key).await.is_err());
+        assert_eq!(circuit(&resilient), "open");
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(resilient.pokemon(&key).await.is_ok());
+        assert_eq!(circuit(&resilient), "closed");
+        assert_eq!(source.calls(), 4);
+    }
+}
@@ -0,0 +1,12 @@
+//! Shared with the `import_pokemon` binary, which writes a column for each
+//! of these
+
+/// The base stats PokeAPI lists for every Pokémon
+pub const STAT_NAMES: [&str; 6] = [
+    "hp",
+    "attack",
+    "defense",
+    "special-attack",
+    "special-defense",
+    "speed",
+];
@@ -10,13 +10,13 @@ async fn cube_bits(path: web::Path<String>) -> EndpointRet {
 
     let nums: Vec<i64> = ids
         .split('/')
-        .map(|n| return n.parse::<i64>().unwrap_or(0))
+        .map(|n| n.parse::<i64>().unwrap_or(0))
         .collect();
 
     let mut res = 0;
 
     for num in nums {
-        res = res ^ num;
+        res ^= num;
     }
 
     res = res.pow(3);
@@ -1,47 +1,271 @@
+use std::io::Cursor;
+
 use actix_multipart::form::{tempfile::TempFile, MultipartForm};
-use actix_web::{post, HttpResponse};
-use image::{io::Reader as ImageRader, Rgb};
+use actix_web::{post, web, HttpResponse};
+use image::{
+    codecs::jpeg::JpegEncoder,
+    io::{Limits, Reader as ImageRader},
+    DynamicImage, ImageError, ImageFormat, Pixel, Rgba, RgbaImage,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::common::{EndpointRet, ServerError};
 
+mod archive;
+pub(super) mod assets;
+pub(super) mod batch;
+mod color;
+mod exif;
+pub(super) mod mask;
+pub(super) mod metadata;
+mod perceptual;
+pub(super) mod similar;
+pub(super) mod transform;
+
+use color::{Channel, Predicate, PredicateParams};
+
+const DEFAULT_BINS: usize = 16;
+const MAX_WIDTH: u32 = 16_384;
+const MAX_HEIGHT: u32 = 16_384;
+/// 160 MiB once converted to RGBA
+const MAX_PIXELS: u64 = 40_000_000;
+/// For the decoder itself, in case a format's header doesn't tell the whole
+/// story
+const MAX_ALLOC: u64 = 512 * 1024 * 1024;
+/// Re-encoding shouldn't cost more quality than needed
+const JPEG_QUALITY: u8 = 90;
+
 #[derive(Debug, MultipartForm)]
 struct UploadForm {
     image: TempFile,
 }
 
-#[post("11/red_pixels")]
-async fn red_pixels(MultipartForm(form): MultipartForm<UploadForm>) -> EndpointRet {
-    // A beautiful nest of match statements and error handling
-    // Cant use the shorthand '?' because that can't be mapped to ServerError
-    // TODO figure out why '?' can't map to ServerError
-    // TODO impl the necessary trait to cast ? to ServerErrro
-    let img = match ImageRader::open(form.image.file) {
-        Ok(reader) => match reader.with_guessed_format() {
-            Ok(file) => match file.decode() {
-                Ok(image) => image,
-                Err(_) => return Err(ServerError::InternalError),
-            },
-            Err(_) => return Err(ServerError::InternalError),
-        },
-        Err(_) => return Err(ServerError::InternalError),
+fn check_dimensions(width: u32, height: u32) -> Result<(), ServerError> {
+    let too_large = |limit: String| {
+        Err(ServerError::PayloadTooLarge(format!(
+            "the image is {width}x{height}, {limit}"
+        )))
     };
 
-    // Have to take the image as rgb8, so we also have to cast
-    // all of the numbers to usize to get around overflows
-    let pixels = match img.as_rgb8() {
-        Some(img) => img.pixels(),
-        None => return Err(ServerError::InternalError),
+    if width > MAX_WIDTH {
+        return too_large(format!("at most {MAX_WIDTH} pixels wide are allowed"));
+    }
+    if height > MAX_HEIGHT {
+        return too_large(format!("at most {MAX_HEIGHT} pixels high are allowed"));
+    }
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return too_large(format!("at most {MAX_PIXELS} pixels are allowed"));
+    }
+
+    Ok(())
+}
+
+/// Reads any format the image crate knows, whatever the file name says. The
+/// header is checked against the limits before a single pixel is decoded.
+fn decode(bytes: &[u8]) -> Result<DynamicImage, ServerError> {
+    let unreadable = || ServerError::UnprocessableEntity("not a readable image".to_owned());
+    let reader = || {
+        ImageRader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(|_| ServerError::InternalError)
     };
 
-    let count = pixels
-        .filter(|Rgb([red, green, blue])| {
-            let r = *red as usize;
-            let g = *green as usize;
-            let b = *blue as usize;
+    let (width, height) = reader()?.into_dimensions().map_err(|_| unreadable())?;
+    check_dimensions(width, height)?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_WIDTH);
+    limits.max_image_height = Some(MAX_HEIGHT);
+    limits.max_alloc = Some(MAX_ALLOC);
+
+    let mut reader = reader()?;
+    reader.limits(limits);
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => ServerError::PayloadTooLarge(format!(
+            "decoding the image needs more than {} MiB",
+            MAX_ALLOC / 1024 / 1024
+        )),
+        _ => unreadable(),
+    })
+}
+
+/// Writes the pixels in `format`, and nothing else. JPEG has no alpha and
+/// the WebP encoder only takes 8 bit RGB(A), so those are converted first.
+fn encode(img: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY)
+            .encode_image(&DynamicImage::ImageRgb8(img.into_rgb8()))?,
+        ImageFormat::WebP => {
+            DynamicImage::ImageRgba8(img.into_rgba8()).write_to(&mut out, format)?
+        }
+        _ => img.write_to(&mut out, format)?,
+    }
+
+    Ok(out.into_inner())
+}
+
+/// Reads the upload and hands its bytes to `work`, both on the blocking pool
+/// as neither belongs on the async workers
+async fn with_upload_bytes<T, F>(file: TempFile, work: F) -> Result<T, ServerError>
+where
+    T: Send + 'static,
+    F: FnOnce(Vec<u8>) -> Result<T, ServerError> + Send + 'static,
+{
+    web::block(move || {
+        let bytes = std::fs::read(file.file.path()).map_err(|_| ServerError::InternalError)?;
+        work(bytes)
+    })
+    .await
+    .map_err(|_| ServerError::InternalError)?
+}
+
+/// Same as `with_upload_bytes`, with the upload decoded
+async fn with_upload<T, F>(file: TempFile, work: F) -> Result<T, ServerError>
+where
+    T: Send + 'static,
+    F: FnOnce(DynamicImage) -> Result<T, ServerError> + Send + 'static,
+{
+    with_upload_bytes(file, move |bytes| work(decode(&bytes)?)).await
+}
+
+/// Every pixel, split across the rayon pool
+fn par_pixels(img: &RgbaImage) -> impl IndexedParallelIterator<Item = &Rgba<u8>> {
+    img.as_raw()
+        .par_chunks_exact(Rgba::<u8>::CHANNEL_COUNT as usize)
+        .map(Rgba::from_slice)
+}
+
+#[derive(Serialize)]
+struct Histogram {
+    bins: usize,
+    red: Vec<u64>,
+    green: Vec<u64>,
+    blue: Vec<u64>,
+}
+
+impl Histogram {
+    fn new(bins: usize) -> Self {
+        Histogram {
+            bins,
+            red: vec![0; bins],
+            green: vec![0; bins],
+            blue: vec![0; bins],
+        }
+    }
+
+    fn add(&mut self, [r, g, b]: [u8; 3]) {
+        let bin = |c: u8| c as usize * self.bins / 256;
 
-            return r > g + b;
+        self.red[bin(r)] += 1;
+        self.green[bin(g)] += 1;
+        self.blue[bin(b)] += 1;
+    }
+
+    fn merge(mut self, other: &Self) -> Self {
+        for (mine, theirs) in [
+            (&mut self.red, &other.red),
+            (&mut self.green, &other.green),
+            (&mut self.blue, &other.blue),
+        ] {
+            mine.iter_mut().zip(theirs).for_each(|(a, b)| *a += b);
+        }
+
+        self
+    }
+}
+
+fn percentage(matched: u64, pixels: u64) -> f64 {
+    if pixels == 0 {
+        0.0
+    } else {
+        matched as f64 * 100.0 / pixels as f64
+    }
+}
+
+#[derive(Serialize)]
+struct PixelStats {
+    width: u32,
+    height: u32,
+    pixels: u64,
+    matched: u64,
+    percentage: f64,
+    histogram: Histogram,
+}
+
+fn pixel_stats(img: &RgbaImage, predicate: &Predicate, bins: usize) -> PixelStats {
+    let empty = || (0, Histogram::new(bins));
+    let (matched, histogram) = par_pixels(img)
+        .fold(empty, |(matched, mut histogram), pixel| {
+            let [r, g, b, _] = pixel.0;
+            histogram.add([r, g, b]);
+
+            (matched + predicate.matches(pixel) as u64, histogram)
         })
-        .count();
+        .reduce(empty, |(m1, h1), (m2, h2)| (m1 + m2, h1.merge(&h2)));
+
+    let pixels = img.width() as u64 * img.height() as u64;
+    PixelStats {
+        width: img.width(),
+        height: img.height(),
+        pixels,
+        matched,
+        percentage: percentage(matched, pixels),
+        histogram,
+    }
+}
+
+#[post("11/red_pixels")]
+async fn red_pixels(MultipartForm(form): MultipartForm<UploadForm>) -> EndpointRet {
+    // Everything goes through RGBA, so grayscale, palette, 16 bit and
+    // transparent images are counted the same way
+    let predicate = Predicate::Dominant(Channel::Red);
+    let count = with_upload(form.image, move |img| {
+        let img = img.into_rgba8();
+        Ok(par_pixels(&img).filter(|p| predicate.matches(p)).count())
+    })
+    .await?;
 
     Ok(HttpResponse::Ok().body(count.to_string()))
 }
+
+fn default_bins() -> usize {
+    DEFAULT_BINS
+}
+
+#[derive(Deserialize)]
+struct HistogramParams {
+    /// Per channel
+    #[serde(default = "default_bins")]
+    bins: usize,
+}
+
+impl HistogramParams {
+    fn bins(&self) -> Result<usize, ServerError> {
+        if !(1..=256).contains(&self.bins) {
+            return Err(ServerError::BadRequest(
+                "bins must be between 1 and 256".to_owned(),
+            ));
+        }
+
+        Ok(self.bins)
+    }
+}
+
+#[post("11/analyze")]
+async fn analyze_colors(
+    MultipartForm(form): MultipartForm<UploadForm>,
+    params: web::Query<PredicateParams>,
+    histogram: web::Query<HistogramParams>,
+) -> EndpointRet {
+    let predicate = params.predicate()?;
+    let bins = histogram.bins()?;
+
+    let stats = with_upload(form.image, move |img| {
+        Ok(pixel_stats(&img.into_rgba8(), &predicate, bins))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(stats))
+}
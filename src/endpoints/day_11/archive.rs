@@ -0,0 +1,325 @@
+//! Pulls images out of zip, tar and tar.gz uploads. Entries are read into
+//! memory, so every one of them counts against a shared budget.
+
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::common::ServerError;
+
+/// A file pulled out of an archive, or why it couldn't be
+pub struct Entry {
+    pub name: String,
+    pub bytes: Result<Vec<u8>, ServerError>,
+}
+
+/// How much may still be unpacked, shared by all archives of a request
+pub struct Budget {
+    pub left: usize,
+    pub per_entry: usize,
+    /// Files, whether they come out of an archive or not
+    pub files: usize,
+}
+
+impl Budget {
+    /// Stops unpacking as soon as there are too many files, rather than
+    /// after a huge listing has been read
+    pub fn count_file(&mut self) -> Result<(), ServerError> {
+        match self.files.checked_sub(1) {
+            Some(files) => {
+                self.files = files;
+                Ok(())
+            }
+            None => Err(ServerError::BadRequest(
+                "the batch has too many images".to_owned(),
+            )),
+        }
+    }
+
+    /// Reads a decoded entry to its end. Declared sizes are never trusted,
+    /// the reader is cut one byte past what may still be unpacked.
+    fn read(&mut self, reader: impl Read, kind: &str) -> Result<Vec<u8>, ServerError> {
+        let limit = self.per_entry.min(self.left);
+        let mut out = Vec::new();
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|_| invalid(kind))?;
+
+        if out.len() > self.per_entry {
+            return Err(ServerError::PayloadTooLarge(format!(
+                "archive entries can be at most {} MiB unpacked",
+                self.per_entry / 1024 / 1024
+            )));
+        }
+        if out.len() > self.left {
+            return Err(ServerError::PayloadTooLarge(
+                "the batch is too large once unpacked".to_owned(),
+            ));
+        }
+
+        self.left -= out.len();
+        Ok(out)
+    }
+}
+
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Kind {
+    /// Goes by the content, names lie
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        // Local file header, or the end record of an empty archive
+        if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+            Some(Kind::Zip)
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Kind::TarGz)
+        } else if bytes.get(257..262) == Some(b"ustar") {
+            Some(Kind::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Lists the regular files, an archive that can't be read at all is an
+    /// error, single broken entries are not
+    pub fn entries(&self, bytes: &[u8], budget: &mut Budget) -> Result<Vec<Entry>, ServerError> {
+        match self {
+            Kind::Zip => zip_entries(bytes, budget),
+            Kind::Tar => tar_entries(bytes, budget),
+            Kind::TarGz => {
+                // The tar is kept in memory, so it counts as well
+                let tar = Budget {
+                    left: budget.left,
+                    per_entry: usize::MAX,
+                    files: budget.files,
+                }
+                .read(GzDecoder::new(bytes), "gzip")?;
+
+                let mut inner = Budget {
+                    left: budget.left - tar.len(),
+                    per_entry: budget.per_entry,
+                    files: budget.files,
+                };
+                let entries = tar_entries(&tar, &mut inner);
+                budget.left = inner.left;
+                budget.files = inner.files;
+
+                entries
+            }
+        }
+    }
+}
+
+fn invalid(kind: &str) -> ServerError {
+    ServerError::UnprocessableEntity(format!("not a valid {kind} archive"))
+}
+
+/// Dot files and the resource forks macOS adds to zips are no images
+fn skipped(name: &str) -> bool {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    name.ends_with('/') || base.is_empty() || base.starts_with('.') || name.starts_with("__MACOSX/")
+}
+
+fn zip_error(e: ZipError) -> ServerError {
+    match e {
+        ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED) => {
+            ServerError::UnprocessableEntity("encrypted zip entries are not supported".to_owned())
+        }
+        ZipError::UnsupportedArchive(_) => {
+            ServerError::UnprocessableEntity("zip compression method is not supported".to_owned())
+        }
+        _ => invalid("zip"),
+    }
+}
+
+fn zip_entries(bytes: &[u8], budget: &mut Budget) -> Result<Vec<Entry>, ServerError> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(zip_error)?;
+
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        // The raw entry is enough for the name, it's neither decrypted nor
+        // decompressed
+        let name = archive
+            .by_index_raw(i)
+            .map(|file| file.name().to_owned())
+            .map_err(zip_error)?;
+        if skipped(&name) {
+            continue;
+        }
+
+        budget.count_file()?;
+        // The CRC is checked by the reader once it reaches the end
+        let bytes = archive
+            .by_index(i)
+            .map_err(zip_error)
+            .and_then(|file| budget.read(file, "zip"));
+        entries.push(Entry { name, bytes });
+    }
+
+    Ok(entries)
+}
+
+fn tar_entries(bytes: &[u8], budget: &mut Budget) -> Result<Vec<Entry>, ServerError> {
+    let mut archive = tar::Archive::new(bytes);
+
+    let mut entries = Vec::new();
+    // GNU long names and pax headers are applied by the reader
+    for entry in archive.entries().map_err(|_| invalid("tar"))? {
+        let entry = entry.map_err(|_| invalid("tar"))?;
+        // Directories, links and the like
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = entry
+            .path()
+            .map_err(|_| invalid("tar"))?
+            .to_string_lossy()
+            .into_owned();
+        if skipped(&name) {
+            continue;
+        }
+
+        budget.count_file()?;
+        let size = entry.size();
+        let bytes = budget
+            .read(entry, "tar")
+            .and_then(|data| match data.len() as u64 {
+                len if len == size => Ok(data),
+                // The archive ended in the middle of the entry
+                _ => Err(invalid("tar")),
+            });
+        entries.push(Entry { name, bytes });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use actix_web::ResponseError;
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    fn budget() -> Budget {
+        Budget {
+            left: 1024 * 1024,
+            per_entry: 64 * 1024,
+            files: 8,
+        }
+    }
+
+    fn zip(files: &[(&str, CompressionMethod, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, method, data) in files {
+            let options = FileOptions::default().compression_method(*method);
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack(bytes: &[u8]) -> Vec<Entry> {
+        Kind::sniff(bytes)
+            .expect("not an archive")
+            .entries(bytes, &mut budget())
+            .unwrap()
+    }
+
+    #[test]
+    fn zip_reads_stored_and_deflated_entries() {
+        let archive = zip(&[
+            ("a.png", CompressionMethod::Stored, b"stored data"),
+            (".DS_Store", CompressionMethod::Stored, b"skipped"),
+            ("dir/b.png", CompressionMethod::Deflated, &[7; 1000]),
+        ]);
+
+        let entries = unpack(&archive);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.png");
+        assert_eq!(entries[0].bytes.as_deref().unwrap(), b"stored data");
+        assert_eq!(entries[1].name, "dir/b.png");
+        assert_eq!(entries[1].bytes.as_deref().unwrap(), &[7; 1000][..]);
+    }
+
+    #[test]
+    fn zip_checks_the_crc() {
+        let mut archive = zip(&[("a.png", CompressionMethod::Stored, b"stored data")]);
+        let at = archive.windows(6).position(|w| w == b"stored").unwrap();
+        archive[at] = b'S';
+
+        let entries = unpack(&archive);
+        assert_eq!(entries[0].bytes.as_ref().unwrap_err().status_code(), 422);
+    }
+
+    #[test]
+    fn zip_entries_count_against_the_budget() {
+        let archive = zip(&[("bomb.png", CompressionMethod::Deflated, &[0; 100_000])]);
+
+        let entries = unpack(&archive);
+        assert_eq!(entries[0].bytes.as_ref().unwrap_err().status_code(), 413);
+    }
+
+    #[test]
+    fn zip_stops_at_too_many_files() {
+        let names: Vec<_> = (0..9).map(|i| format!("{i}.png")).collect();
+        let files: Vec<_> = names
+            .iter()
+            .map(|name| (name.as_str(), CompressionMethod::Stored, &b""[..]))
+            .collect();
+        let archive = zip(&files);
+
+        let result = Kind::Zip.entries(&archive, &mut budget());
+        assert_eq!(result.err().unwrap().status_code(), 400);
+    }
+
+    #[test]
+    fn tar_reads_regular_files_and_long_names() {
+        let long = format!("{}/c.png", "d".repeat(150));
+        let entries = unpack(&tar(&[("a.png", b"first"), (&long, b"second")]));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].bytes.as_deref().unwrap(), b"first");
+        assert_eq!(entries[1].name, long);
+        assert_eq!(entries[1].bytes.as_deref().unwrap(), b"second");
+    }
+
+    #[test]
+    fn tar_rejects_truncated_entries() {
+        let archive = tar(&[("a.png", &[1; 2000])]);
+
+        let result = Kind::Tar.entries(&archive[..1000], &mut budget());
+        assert_eq!(result.err().unwrap().status_code(), 422);
+    }
+
+    #[test]
+    fn tar_gz_is_unpacked() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tar(&[("a.png", b"zipped")])).unwrap();
+
+        let entries = unpack(&encoder.finish().unwrap());
+        assert_eq!(entries[0].bytes.as_deref().unwrap(), b"zipped");
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use actix_files::HttpRange;
+use actix_multipart::form::MultipartForm;
+use actix_web::{
+    delete, get,
+    http::header::{self, EntityTag, Header},
+    post, route, web, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use image::io::Reader as ImageRader;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use tempfile::NamedTempFile;
+
+use super::{
+    check_dimensions, decode,
+    perceptual::{self, Hashes},
+    with_upload_bytes, UploadForm,
+};
+use crate::common::{pagination::PageParams, AppState, EndpointRet, ServerError};
+
+/// Shipped with the server, read only
+const BUNDLED_DIR: &str = "assets";
+/// Uploaded through the API, named after a hash of their content
+const STORE_DIR: &str = "uploads/day_11";
+const MAX_ASSET_SIZE: usize = 16 * 1024 * 1024;
+/// A content hash name never points at anything else
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Asset {
+    name: String,
+    sha256: String,
+    mime: String,
+    width: i32,
+    height: i32,
+    size: i64,
+    uploaded_at: DateTime<Utc>,
+}
+
+/// Only `Authorization: Bearer <ASSETS_TOKEN>` may change or list assets,
+/// nobody may without a configured token
+pub(super) fn authorize(req: &HttpRequest, state: &AppState) -> Result<(), ServerError> {
+    let Some(token) = &state.assets_token else {
+        return Err(ServerError::Forbidden(
+            "managing assets is disabled on this server".to_owned(),
+        ));
+    };
+
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ServerError::Unauthorized("missing bearer token".to_owned()))?;
+
+    // Comparing digests, the time taken tells nothing about the token itself
+    if Sha256::digest(given.trim()) != Sha256::digest(token) {
+        return Err(ServerError::Forbidden("wrong bearer token".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// The sha256 and type of an uploaded asset
+type Known = (String, String);
+
+/// Finds an asset by name. Uploaded ones only exist while they have a row,
+/// a file left in the store without one is never served.
+pub(super) async fn find_asset(
+    pool: &PgPool,
+    name: &str,
+) -> Result<(PathBuf, Option<Known>), ServerError> {
+    let not_found = || ServerError::NotFound(format!("no asset named {name}"));
+
+    // Only plain file names, nothing that could leave the asset directories
+    let plain = !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+    if !plain {
+        return Err(not_found());
+    }
+
+    let known: Option<Known> = sqlx::query_as("SELECT sha256, mime FROM assets WHERE name = $1;")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let dir = if known.is_some() {
+        STORE_DIR
+    } else {
+        BUNDLED_DIR
+    };
+    let path = Path::new(dir).join(name);
+    if !path.is_file() {
+        return Err(not_found());
+    }
+
+    Ok((path, known))
+}
+
+#[get("/11/assets")]
+async fn list_assets(
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    authorize(&req, &state)?;
+
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM assets;")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let page = page.page(total as usize)?;
+    let range = page.range();
+
+    let assets: Vec<Asset> = sqlx::query_as(
+        "SELECT name, sha256, mime, width, height, size, uploaded_at FROM assets
+        ORDER BY uploaded_at, name LIMIT $1 OFFSET $2;",
+    )
+    .bind(range.len() as i64)
+    .bind(range.start as i64)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    Ok(page.respond(&req).json(json!({
+        "assets": assets,
+        "total": page.total,
+        "next_cursor": page.next_cursor(),
+    })))
+}
+
+/// What an upload turns into before it's stored
+struct Sniffed {
+    bytes: Vec<u8>,
+    sha256: String,
+    mime: &'static str,
+    extension: &'static str,
+    width: u32,
+    height: u32,
+    hashes: Hashes,
+}
+
+/// Tells the format from the content, the file name and the client's
+/// content type are not to be trusted. The header is checked before the
+/// pixels are decoded for the hashes.
+fn sniff(bytes: Vec<u8>) -> Result<Sniffed, ServerError> {
+    let format = image::guess_format(&bytes)
+        .map_err(|_| ServerError::UnprocessableEntity("not an image format we know".to_owned()))?;
+    let (width, height) = ImageRader::with_format(Cursor::new(&bytes), format)
+        .into_dimensions()
+        .map_err(|_| ServerError::UnprocessableEntity("not a readable image".to_owned()))?;
+    check_dimensions(width, height)?;
+    let hashes = perceptual::hashes(&decode(&bytes)?);
+
+    Ok(Sniffed {
+        sha256: format!("{:x}", Sha256::digest(&bytes)),
+        mime: format.to_mime_type(),
+        extension: format.extensions_str().first().copied().unwrap_or("bin"),
+        bytes,
+        width,
+        height,
+        hashes,
+    })
+}
+
+/// Holds off every other upload or delete of `name` until the transaction
+/// ends, so a file is never removed between an upload finding it and
+/// inserting its row
+async fn lock_name(
+    transaction: &mut Transaction<'_, Postgres>,
+    name: &str,
+) -> Result<(), ServerError> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1));")
+        .bind(name)
+        .execute(transaction.as_mut())
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    Ok(())
+}
+
+/// Stores the content under its hash, unless it's already there
+fn store(asset: &Sniffed, name: &str) -> Result<(), ServerError> {
+    let path = Path::new(STORE_DIR).join(name);
+    if path.is_file() {
+        return Ok(());
+    }
+
+    // Every upload gets a temporary file of its own, so downloads never see
+    // half a file, even with the same content uploaded twice at once
+    std::fs::create_dir_all(STORE_DIR)
+        .and_then(|_| NamedTempFile::new_in(STORE_DIR))
+        .and_then(|mut file| file.write_all(&asset.bytes).map(|_| file))
+        .and_then(|file| file.persist(&path).map_err(|e| e.error))
+        .map(|_| ())
+        .map_err(|_| ServerError::InternalError)
+}
+
+#[post("/11/assets")]
+async fn upload_asset(
+    req: HttpRequest,
+    MultipartForm(form): MultipartForm<UploadForm>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    authorize(&req, &state)?;
+
+    if form.image.size > MAX_ASSET_SIZE {
+        return Err(ServerError::PayloadTooLarge(format!(
+            "assets can be at most {} MiB",
+            MAX_ASSET_SIZE / 1024 / 1024
+        )));
+    }
+
+    let asset = with_upload_bytes(form.image, sniff).await?;
+    let name = format!("{}.{}", asset.sha256, asset.extension);
+
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+    lock_name(&mut transaction, &name).await?;
+
+    let (asset, name) = web::block(move || store(&asset, &name).map(|_| (asset, name)))
+        .await
+        .map_err(|_| ServerError::InternalError)??;
+
+    // Uploading the same content twice gives back the first upload
+    let inserted: Option<Asset> = sqlx::query_as(
+        "INSERT INTO assets (name, sha256, mime, width, height, size, ahash, dhash, phash)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT (name) DO NOTHING
+        RETURNING name, sha256, mime, width, height, size, uploaded_at;",
+    )
+    .bind(&name)
+    .bind(&asset.sha256)
+    .bind(asset.mime)
+    .bind(asset.width as i32)
+    .bind(asset.height as i32)
+    .bind(asset.bytes.len() as i64)
+    // Postgres has no unsigned integers, the bits are what matters
+    .bind(asset.hashes.ahash as i64)
+    .bind(asset.hashes.dhash as i64)
+    .bind(asset.hashes.phash as i64)
+    .fetch_optional(transaction.as_mut())
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let location = format!("/11/assets/{name}");
+    if let Some(asset) = inserted {
+        return Ok(HttpResponse::Created()
+            .insert_header((header::LOCATION, location))
+            .json(asset));
+    }
+
+    // Assets uploaded before hashing existed get theirs now
+    sqlx::query(
+        "UPDATE assets SET ahash = $2, dhash = $3, phash = $4
+        WHERE name = $1 AND phash IS NULL;",
+    )
+    .bind(&name)
+    .bind(asset.hashes.ahash as i64)
+    .bind(asset.hashes.dhash as i64)
+    .bind(asset.hashes.phash as i64)
+    .execute(&state.pool)
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    let existing: Asset = sqlx::query_as(
+        "SELECT name, sha256, mime, width, height, size, uploaded_at FROM assets WHERE name = $1;",
+    )
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::LOCATION, location))
+        .json(existing))
+}
+
+#[delete("/11/assets/{name}")]
+async fn delete_asset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    authorize(&req, &state)?;
+    let name = path.into_inner();
+
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+    lock_name(&mut transaction, &name).await?;
+
+    let deleted: Option<String> =
+        sqlx::query_scalar("DELETE FROM assets WHERE name = $1 RETURNING name;")
+            .bind(&name)
+            .fetch_optional(transaction.as_mut())
+            .await
+            .map_err(|_| ServerError::InternalError)?;
+
+    // Bundled assets have no row, so they can't be deleted here
+    let Some(name) = deleted else {
+        return Err(ServerError::NotFound(format!(
+            "no uploaded asset named {name}"
+        )));
+    };
+
+    // The file goes while the name is still locked. One left behind is only
+    // wasted space, the row is what makes it exist.
+    let _ = std::fs::remove_file(Path::new(STORE_DIR).join(name));
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Answers conditional and range requests against the strong `etag`
+fn serve(req: &HttpRequest, bytes: Vec<u8>, etag: EntityTag, mime: &str) -> HttpResponse {
+    let not_modified = match header::IfNoneMatch::parse(req) {
+        Ok(header::IfNoneMatch::Any) => true,
+        Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        Err(_) => false,
+    };
+    if not_modified {
+        return HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .finish();
+    }
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header(header::ETag(etag.clone()))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .content_type(mime);
+
+    // A client holding an outdated part gets the whole thing instead
+    let current = !req.headers().contains_key(header::IF_RANGE)
+        || matches!(header::IfRange::parse(req), Ok(header::IfRange::EntityTag(tag)) if tag.strong_eq(&etag));
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| current);
+
+    let Some(range) = range else {
+        return res.body(bytes);
+    };
+
+    let size = bytes.len() as u64;
+    match HttpRange::parse(range, size).as_deref() {
+        Ok([HttpRange { start, length }]) => {
+            let (start, end) = (*start as usize, (*start + *length) as usize);
+
+            res.status(actix_web::http::StatusCode::PARTIAL_CONTENT)
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {start}-{}/{size}", end - 1),
+                ))
+                .body(bytes[start..end].to_vec())
+        }
+        // Several ranges get the whole body, which is always allowed
+        Ok(_) => res.body(bytes),
+        Err(_) => HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{size}")))
+            .finish(),
+    }
+}
+
+#[route("/11/assets/{name}", method = "GET", method = "HEAD")]
+async fn download_asset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let (file, known) = find_asset(&state.pool, &path).await?;
+    let uploaded = known.is_some();
+
+    let (bytes, sha256, mime) = web::block(move || {
+        let bytes = std::fs::read(file).map_err(|_| ServerError::InternalError)?;
+
+        // Bundled assets have no row, their hash and type come from the file
+        let (sha256, mime) = known.unwrap_or_else(|| {
+            let mime = image::guess_format(&bytes)
+                .map(|format| format.to_mime_type())
+                .unwrap_or("application/octet-stream");
+            (format!("{:x}", Sha256::digest(&bytes)), mime.to_owned())
+        });
+
+        Ok::<_, ServerError>((bytes, sha256, mime))
+    })
+    .await
+    .map_err(|_| ServerError::InternalError)??;
+
+    let mut res = serve(&req, bytes, EntityTag::new_strong(sha256), &mime);
+    if uploaded {
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static(IMMUTABLE),
+        );
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,164 @@
+use std::sync::OnceLock;
+
+use actix_multipart::form::{tempfile::TempFile, MultipartForm};
+use actix_web::{post, web, HttpResponse, ResponseError};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use serde::Serialize;
+use serde_json::json;
+
+use super::{
+    archive::{Budget, Entry, Kind},
+    color::{Predicate, PredicateParams},
+    decode, percentage, pixel_stats, Histogram, HistogramParams, PixelStats,
+};
+use crate::common::{EndpointRet, ServerError};
+
+const MAX_FILES: usize = 256;
+const MAX_ENTRY_SIZE: usize = 32 * 1024 * 1024;
+/// For everything unpacked from the archives of one batch
+const MAX_UNPACKED: usize = 256 * 1024 * 1024;
+/// Decoding limits are per image, so only this many are decoded at once
+/// whatever the number of cores
+const DECODE_THREADS: usize = 4;
+
+fn decode_pool() -> Option<&'static ThreadPool> {
+    static POOL: OnceLock<Option<ThreadPool>> = OnceLock::new();
+
+    POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(DECODE_THREADS)
+            .thread_name(|i| format!("day-11-batch-{i}"))
+            .build()
+            .ok()
+    })
+    .as_ref()
+}
+
+#[derive(MultipartForm)]
+struct BatchForm {
+    /// Images, or tar, tar.gz and zip archives of them
+    images: Vec<TempFile>,
+}
+
+#[derive(Serialize)]
+struct BatchItem {
+    /// Archive entries are named `<archive>/<path in the archive>`
+    name: String,
+    status: u16,
+    #[serde(flatten)]
+    stats: Option<PixelStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Reads the uploads and unpacks the archives among them, in upload order
+fn unpack(files: Vec<TempFile>) -> Result<Vec<Entry>, ServerError> {
+    let mut budget = Budget {
+        left: MAX_UNPACKED,
+        per_entry: MAX_ENTRY_SIZE,
+        files: MAX_FILES,
+    };
+    let mut entries = Vec::new();
+
+    for (i, file) in files.into_iter().enumerate() {
+        let name = file.file_name.unwrap_or_else(|| format!("image {}", i + 1));
+        let bytes = std::fs::read(file.file.path()).map_err(|_| ServerError::InternalError)?;
+
+        match Kind::sniff(&bytes).map(|kind| kind.entries(&bytes, &mut budget)) {
+            None => {
+                budget.count_file()?;
+                entries.push(Entry {
+                    name,
+                    bytes: Ok(bytes),
+                })
+            }
+            Some(Ok(unpacked)) => entries.extend(unpacked.into_iter().map(|entry| Entry {
+                name: format!("{name}/{}", entry.name),
+                bytes: entry.bytes,
+            })),
+            // Too many files is the batch's fault, not this archive's
+            Some(Err(ServerError::BadRequest(e))) => return Err(ServerError::BadRequest(e)),
+            Some(Err(e)) => entries.push(Entry {
+                name,
+                bytes: Err(e),
+            }),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn analyze(entry: Entry, predicate: &Predicate, bins: usize) -> BatchItem {
+    // The encoded bytes are dropped as soon as they're decoded
+    let stats = entry
+        .bytes
+        .and_then(|bytes| decode(&bytes))
+        .map(|img| pixel_stats(&img.into_rgba8(), predicate, bins));
+
+    match stats {
+        Ok(stats) => BatchItem {
+            name: entry.name,
+            status: 200,
+            stats: Some(stats),
+            error: None,
+        },
+        Err(e) => BatchItem {
+            name: entry.name,
+            status: e.status_code().as_u16(),
+            stats: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+#[post("/11/batch")]
+async fn analyze_batch(
+    MultipartForm(form): MultipartForm<BatchForm>,
+    params: web::Query<PredicateParams>,
+    histogram: web::Query<HistogramParams>,
+) -> EndpointRet {
+    let predicate = params.predicate()?;
+    let bins = histogram.bins()?;
+    if form.images.is_empty() {
+        return Err(ServerError::BadRequest(
+            "send the images as 'images' fields".to_owned(),
+        ));
+    }
+
+    // One file per task, a broken one only fails its own item
+    let files = form.images;
+    let results = web::block(move || {
+        let entries = unpack(files)?;
+        let pool = decode_pool().ok_or(ServerError::InternalError)?;
+
+        // Entries are moved out one by one, so each one's bytes go away
+        // once it has been analyzed
+        Ok::<_, ServerError>(pool.install(|| {
+            entries
+                .into_par_iter()
+                .map(|entry| analyze(entry, &predicate, bins))
+                .collect::<Vec<_>>()
+        }))
+    })
+    .await
+    .map_err(|_| ServerError::InternalError)??;
+
+    let analyzed: Vec<_> = results.iter().filter_map(|r| r.stats.as_ref()).collect();
+    let pixels = analyzed.iter().map(|stats| stats.pixels).sum();
+    let matched = analyzed.iter().map(|stats| stats.matched).sum();
+    let histogram = analyzed.iter().fold(Histogram::new(bins), |total, stats| {
+        total.merge(&stats.histogram)
+    });
+
+    Ok(HttpResponse::Ok().json(json!({
+        "succeeded": analyzed.len(),
+        "failed": results.len() - analyzed.len(),
+        "totals": {
+            "pixels": pixels,
+            "matched": matched,
+            "percentage": percentage(matched, pixels),
+            "histogram": histogram,
+        },
+        "results": results,
+    })))
+}
@@ -0,0 +1,181 @@
+use image::Rgba;
+use serde::Deserialize;
+
+use crate::common::ServerError;
+
+const DEFAULT_MAX_DISTANCE: f32 = 64.0;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    #[default]
+    Red,
+    Green,
+    Blue,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum PredicateKind {
+    #[default]
+    Dominant,
+    Hsv,
+    Distance,
+}
+
+/// Query parameters picking which pixels count
+#[derive(Deserialize)]
+pub struct PredicateParams {
+    #[serde(default)]
+    predicate: PredicateKind,
+    /// For `dominant`
+    #[serde(default)]
+    channel: Channel,
+    /// For `hsv`, hue in degrees wrapping around when `h_min > h_max`,
+    /// saturation and value from 0 to 1
+    h_min: Option<f32>,
+    h_max: Option<f32>,
+    s_min: Option<f32>,
+    s_max: Option<f32>,
+    v_min: Option<f32>,
+    v_max: Option<f32>,
+    /// For `distance`, a `#rrggbb` color
+    target: Option<String>,
+    /// Euclidean distance in RGB space
+    max_distance: Option<f32>,
+}
+
+pub enum Predicate {
+    /// The channel is brighter than the other two together, the challenge's
+    /// "magical red" for `Red`
+    Dominant(Channel),
+    Hsv {
+        hue: (f32, f32),
+        saturation: (f32, f32),
+        value: (f32, f32),
+    },
+    Distance {
+        target: [u8; 3],
+        max: f32,
+    },
+}
+
+pub fn parse_hex(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn range(
+    min: Option<f32>,
+    max: Option<f32>,
+    (lowest, highest): (f32, f32),
+    name: &str,
+) -> Result<(f32, f32), ServerError> {
+    let (min, max) = (min.unwrap_or(lowest), max.unwrap_or(highest));
+    let valid = |x: f32| (lowest..=highest).contains(&x);
+
+    if !valid(min) || !valid(max) {
+        return Err(ServerError::BadRequest(format!(
+            "{name} bounds must be between {lowest} and {highest}"
+        )));
+    }
+
+    Ok((min, max))
+}
+
+impl PredicateParams {
+    pub fn predicate(&self) -> Result<Predicate, ServerError> {
+        match self.predicate {
+            PredicateKind::Dominant => Ok(Predicate::Dominant(self.channel)),
+            PredicateKind::Hsv => {
+                let hue = range(self.h_min, self.h_max, (0.0, 360.0), "hue")?;
+                let saturation = range(self.s_min, self.s_max, (0.0, 1.0), "saturation")?;
+                let value = range(self.v_min, self.v_max, (0.0, 1.0), "value")?;
+
+                Ok(Predicate::Hsv {
+                    hue,
+                    saturation,
+                    value,
+                })
+            }
+            PredicateKind::Distance => {
+                let target = self.target.as_deref().and_then(parse_hex).ok_or_else(|| {
+                    ServerError::BadRequest("distance needs a #rrggbb target".to_owned())
+                })?;
+                let max = self.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE);
+                if !max.is_finite() || max < 0.0 {
+                    return Err(ServerError::BadRequest(
+                        "max_distance can't be negative".to_owned(),
+                    ));
+                }
+
+                Ok(Predicate::Distance { target, max })
+            }
+        }
+    }
+}
+
+/// Hue in degrees, saturation and value from 0 to 1
+fn hsv([r, g, b]: [u8; 3]) -> (f32, f32, f32) {
+    let [r, g, b] = [r, g, b].map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+
+    (hue, saturation, max)
+}
+
+impl Predicate {
+    /// Fully transparent pixels never match, whatever color they store
+    pub fn matches(&self, &Rgba([r, g, b, a]): &Rgba<u8>) -> bool {
+        if a == 0 {
+            return false;
+        }
+
+        // Sums of two channels don't fit in a u8
+        let [wr, wg, wb] = [r, g, b].map(u16::from);
+        match *self {
+            Predicate::Dominant(Channel::Red) => wr > wg + wb,
+            Predicate::Dominant(Channel::Green) => wg > wr + wb,
+            Predicate::Dominant(Channel::Blue) => wb > wr + wg,
+            Predicate::Hsv {
+                hue: (h_min, h_max),
+                saturation,
+                value,
+            } => {
+                let (h, s, v) = hsv([r, g, b]);
+                let hue_matches = if h_min <= h_max {
+                    (h_min..=h_max).contains(&h)
+                } else {
+                    h >= h_min || h <= h_max
+                };
+
+                hue_matches
+                    && (saturation.0..=saturation.1).contains(&s)
+                    && (value.0..=value.1).contains(&v)
+            }
+            Predicate::Distance {
+                target: [tr, tg, tb],
+                max,
+            } => {
+                let d = |x: u8, y: u8| (x as f32 - y as f32).powi(2);
+                (d(r, tr) + d(g, tg) + d(b, tb)).sqrt() <= max
+            }
+        }
+    }
+}
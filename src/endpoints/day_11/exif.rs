@@ -0,0 +1,109 @@
+//! EXIF tags as text and numbers, out of whatever container the image
+//! keeps them in
+
+use std::io::Cursor;
+
+pub use exif::Tag;
+use exif::{In, Reader, Value};
+
+pub struct Exif(exif::Exif);
+
+impl Exif {
+    /// `None` for images without EXIF, or with EXIF that can't be read
+    pub fn read(bytes: &[u8]) -> Option<Exif> {
+        Reader::new()
+            .read_from_container(&mut Cursor::new(bytes))
+            .ok()
+            .map(Exif)
+    }
+
+    /// The EXIF and GPS IFDs hang off the main one, their tags tell them
+    /// apart
+    fn value(&self, tag: Tag) -> Option<&Value> {
+        self.0.get_field(tag, In::PRIMARY).map(|field| &field.value)
+    }
+
+    /// Empty strings count as missing, cameras like to write those
+    pub fn text(&self, tag: Tag) -> Option<&str> {
+        match self.value(tag)? {
+            Value::Ascii(texts) => {
+                let text = std::str::from_utf8(texts.first()?).ok()?.trim();
+                (!text.is_empty()).then_some(text)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn numbers(&self, tag: Tag) -> Option<Vec<f64>> {
+        let numbers = match self.value(tag)? {
+            Value::Byte(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::Short(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::Long(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::SByte(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::SShort(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::SLong(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::Float(v) => v.iter().map(|&n| n as f64).collect(),
+            Value::Double(v) => v.clone(),
+            Value::Rational(v) => v.iter().map(|r| r.to_f64()).collect(),
+            Value::SRational(v) => v.iter().map(|r| r.to_f64()).collect(),
+            _ => return None,
+        };
+
+        Some(numbers)
+    }
+
+    /// Divisions by zero in rationals are no numbers either
+    pub fn number(&self, tag: Tag) -> Option<f64> {
+        self.numbers(tag)?
+            .first()
+            .copied()
+            .filter(|n| n.is_finite())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{experimental::Writer, Field, Rational};
+
+    use super::*;
+
+    fn field(tag: Tag, value: Value) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        }
+    }
+
+    #[test]
+    fn reads_text_and_numbers() {
+        let fields = [
+            field(Tag::Make, Value::Ascii(vec![b"Canon ".to_vec()])),
+            field(Tag::LensModel, Value::Ascii(vec![Vec::new()])),
+            field(Tag::Orientation, Value::Short(vec![6])),
+            field(
+                Tag::FNumber,
+                Value::Rational(vec![Rational { num: 28, denom: 0 }]),
+            ),
+            field(
+                Tag::GPSLatitude,
+                Value::Rational(vec![(33, 1).into(), (52, 1).into(), (1800, 100).into()]),
+            ),
+        ];
+
+        for little_endian in [true, false] {
+            let mut writer = Writer::new();
+            fields.iter().for_each(|field| writer.push_field(field));
+            let mut tiff = Cursor::new(Vec::new());
+            writer.write(&mut tiff, little_endian).unwrap();
+
+            let exif = Exif::read(tiff.get_ref()).unwrap();
+            assert_eq!(exif.text(Tag::Make), Some("Canon"));
+            assert_eq!(exif.text(Tag::LensModel), None);
+            assert_eq!(exif.number(Tag::Orientation), Some(6.0));
+            // A zero denominator is no number
+            assert_eq!(exif.number(Tag::FNumber), None);
+            assert_eq!(exif.numbers(Tag::GPSLatitude), Some(vec![33.0, 52.0, 18.0]));
+        }
+    }
+}
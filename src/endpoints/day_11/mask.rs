@@ -0,0 +1,242 @@
+use std::{cmp::Reverse, collections::VecDeque, io::Cursor};
+
+use actix_multipart::form::MultipartForm;
+use actix_web::{post, web, HttpResponse};
+use image::{ImageFormat, Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{
+    color::{parse_hex, Predicate, PredicateParams},
+    par_pixels, with_upload, UploadForm,
+};
+use crate::common::{EndpointRet, ServerError};
+
+const DEFAULT_MAX_REGIONS: usize = 100;
+/// What's left of the brightness of pixels that don't match
+const DIMMED: f32 = 0.25;
+const NEIGHBOURS: [(i64, i64); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum MaskFormat {
+    /// The overlay
+    #[default]
+    Png,
+    /// Bounding box and regions
+    Json,
+}
+
+fn default_max_regions() -> usize {
+    DEFAULT_MAX_REGIONS
+}
+
+#[derive(Deserialize)]
+struct MaskParams {
+    #[serde(default)]
+    format: MaskFormat,
+    /// Paints matching pixels in this `#rrggbb` color instead of their own
+    highlight: Option<String>,
+    /// Regions smaller than this are left out
+    #[serde(default)]
+    min_pixels: usize,
+    #[serde(default = "default_max_regions")]
+    max_regions: usize,
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    pixels: u64,
+}
+
+impl Region {
+    fn at(x: u32, y: u32) -> Self {
+        Region {
+            x,
+            y,
+            width: 1,
+            height: 1,
+            pixels: 0,
+        }
+    }
+
+    fn include(&mut self, x: u32, y: u32) {
+        let (right, bottom) = (self.x + self.width, self.y + self.height);
+
+        self.x = self.x.min(x);
+        self.y = self.y.min(y);
+        self.width = right.max(x + 1) - self.x;
+        self.height = bottom.max(y + 1) - self.y;
+    }
+}
+
+/// Which pixels match, row by row
+struct Mask {
+    width: u32,
+    height: u32,
+    bits: Vec<bool>,
+}
+
+impl Mask {
+    fn get(&self, x: u32, y: u32) -> bool {
+        self.bits[y as usize * self.width as usize + x as usize]
+    }
+
+    fn bounding_box(&self) -> Option<Region> {
+        let mut bounds: Option<Region> = None;
+
+        for y in 0..self.height {
+            for x in (0..self.width).filter(|&x| self.get(x, y)) {
+                let region = bounds.get_or_insert(Region::at(x, y));
+                region.include(x, y);
+                region.pixels += 1;
+            }
+        }
+
+        bounds
+    }
+
+    /// Groups matching pixels touching each other, diagonals included
+    fn regions(&self) -> Vec<Region> {
+        let mut seen = vec![false; self.bits.len()];
+        let mut regions = Vec::new();
+        let mut queue = VecDeque::new();
+
+        for start in 0..self.bits.len() {
+            if !self.bits[start] || seen[start] {
+                continue;
+            }
+
+            let width = self.width as usize;
+            let (x, y) = ((start % width) as u32, (start / width) as u32);
+            let mut region = Region::at(x, y);
+            seen[start] = true;
+            queue.push_back((x, y));
+
+            while let Some((x, y)) = queue.pop_front() {
+                region.include(x, y);
+                region.pixels += 1;
+
+                for (dx, dy) in NEIGHBOURS {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if nx < 0 || ny < 0 || nx >= self.width as i64 || ny >= self.height as i64 {
+                        continue;
+                    }
+
+                    let i = (ny * self.width as i64 + nx) as usize;
+                    if self.bits[i] && !seen[i] {
+                        seen[i] = true;
+                        queue.push_back((nx as u32, ny as u32));
+                    }
+                }
+            }
+
+            regions.push(region);
+        }
+
+        regions
+    }
+}
+
+fn overlay(img: &RgbaImage, mask: &Mask, highlight: Option<[u8; 3]>) -> RgbaImage {
+    RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *img.get_pixel(x, y);
+
+        match (mask.get(x, y), highlight) {
+            (true, Some([hr, hg, hb])) => Rgba([hr, hg, hb, 255]),
+            (true, None) => Rgba([r, g, b, a]),
+            (false, _) => {
+                // Gray, so the highlighted colors stand out
+                let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+                let dimmed = (luma * DIMMED) as u8;
+                Rgba([dimmed, dimmed, dimmed, a])
+            }
+        }
+    })
+}
+
+enum MaskOutput {
+    Png(Vec<u8>),
+    Json(Value),
+}
+
+fn mask_image(
+    img: RgbaImage,
+    predicate: &Predicate,
+    params: &MaskParams,
+    highlight: Option<[u8; 3]>,
+) -> Result<MaskOutput, ServerError> {
+    let mask = Mask {
+        width: img.width(),
+        height: img.height(),
+        bits: par_pixels(&img).map(|p| predicate.matches(p)).collect(),
+    };
+
+    if let MaskFormat::Json = params.format {
+        let mut regions: Vec<_> = mask
+            .regions()
+            .into_iter()
+            .filter(|r| r.pixels >= params.min_pixels as u64)
+            .collect();
+        // Biggest first, so the cut keeps the ones that matter
+        regions.sort_by_key(|r| Reverse(r.pixels));
+        let total = regions.len();
+        regions.truncate(params.max_regions);
+
+        return Ok(MaskOutput::Json(json!({
+            "width": mask.width,
+            "height": mask.height,
+            "matched": mask.bits.iter().filter(|bit| **bit).count(),
+            "bounding_box": mask.bounding_box(),
+            "region_count": total,
+            "regions": regions,
+        })));
+    }
+
+    let mut png = Cursor::new(Vec::new());
+    overlay(&img, &mask, highlight)
+        .write_to(&mut png, ImageFormat::Png)
+        .map_err(|_| ServerError::InternalError)?;
+
+    Ok(MaskOutput::Png(png.into_inner()))
+}
+
+#[post("/11/mask")]
+async fn pixel_mask(
+    MultipartForm(form): MultipartForm<UploadForm>,
+    predicate: web::Query<PredicateParams>,
+    params: web::Query<MaskParams>,
+) -> EndpointRet {
+    let predicate = predicate.predicate()?;
+    let highlight = match params.highlight.as_deref() {
+        Some(color) => Some(parse_hex(color).ok_or_else(|| {
+            ServerError::BadRequest(format!("highlight is not a #rrggbb color: {color}"))
+        })?),
+        None => None,
+    };
+
+    let params = params.into_inner();
+    let output = with_upload(form.image, move |img| {
+        mask_image(img.into_rgba8(), &predicate, &params, highlight)
+    })
+    .await?;
+
+    Ok(match output {
+        MaskOutput::Json(body) => HttpResponse::Ok().json(body),
+        MaskOutput::Png(png) => HttpResponse::Ok().content_type("image/png").body(png),
+    })
+}
@@ -0,0 +1,214 @@
+use std::io::Cursor;
+
+use actix_multipart::form::MultipartForm;
+use actix_web::{post, HttpResponse};
+use chrono::{DateTime, NaiveDateTime};
+use image::{io::Reader as ImageRader, DynamicImage, ImageDecoder, ImageFormat};
+use moxcms::{ColorProfile, ProfileText};
+use serde_json::{json, Value};
+
+use super::{
+    decode, encode,
+    exif::{Exif, Tag},
+    with_upload_bytes, UploadForm,
+};
+use crate::common::{EndpointRet, ServerError};
+
+fn sniff(bytes: &[u8]) -> Result<ImageFormat, ServerError> {
+    image::guess_format(bytes)
+        .map_err(|_| ServerError::UnprocessableEntity("not an image format we know".to_owned()))
+}
+
+/// EXIF dates are local time, with the offset in a tag of its own if at all
+fn created(exif: &Exif) -> Option<String> {
+    [
+        (Tag::DateTimeOriginal, Tag::OffsetTimeOriginal),
+        (Tag::DateTimeDigitized, Tag::OffsetTimeDigitized),
+        (Tag::DateTime, Tag::OffsetTime),
+    ]
+    .into_iter()
+    .find_map(|(date, offset)| {
+        let date = exif.text(date)?;
+        match exif.text(offset) {
+            Some(offset) => {
+                DateTime::parse_from_str(&format!("{date} {offset}"), "%Y:%m:%d %H:%M:%S %:z")
+                    .ok()
+                    .map(|date| date.to_rfc3339())
+            }
+            None => NaiveDateTime::parse_from_str(date, "%Y:%m:%d %H:%M:%S")
+                .ok()
+                .map(|date| date.format("%Y-%m-%dT%H:%M:%S").to_string()),
+        }
+    })
+}
+
+/// Degrees, minutes and seconds to signed decimal degrees
+fn coordinate(exif: &Exif, tag: Tag, reference: Tag, negative: &str) -> Option<f64> {
+    let [degrees, minutes, seconds] = exif.numbers(tag)?[..] else {
+        return None;
+    };
+    let value = degrees + minutes / 60.0 + seconds / 3600.0;
+    if !value.is_finite() {
+        return None;
+    }
+
+    match exif.text(reference) {
+        Some(r) if r.eq_ignore_ascii_case(negative) => Some(-value),
+        _ => Some(value),
+    }
+}
+
+fn gps(exif: &Exif) -> Option<Value> {
+    let latitude = coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S")?;
+    let longitude = coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W")?;
+    // A reference of 1 means below sea level
+    let altitude =
+        exif.number(Tag::GPSAltitude)
+            .map(|altitude| match exif.number(Tag::GPSAltitudeRef) {
+                Some(1.0) => -altitude,
+                _ => altitude,
+            });
+
+    Some(json!({
+        "latitude": latitude,
+        "longitude": longitude,
+        "altitude": altitude,
+    }))
+}
+
+fn orientation(exif: Option<&Exif>) -> Option<u8> {
+    let value = exif?.number(Tag::Orientation)?;
+    (1.0..=8.0).contains(&value).then_some(value as u8)
+}
+
+/// The profile's name, v2 profiles have a `desc` typed one and v4 ones a
+/// multilingual one
+fn icc_description(icc: &[u8]) -> Option<String> {
+    let text = match ColorProfile::new_from_slice(icc).ok()?.description? {
+        ProfileText::PlainString(text) => text,
+        ProfileText::Description(description) => description.ascii_string,
+        // The first record, whatever its language
+        ProfileText::Localizable(records) => records.into_iter().next()?.value,
+    };
+
+    Some(text.trim_end_matches('\0').trim().to_owned())
+}
+
+fn color_profile(icc: Option<&[u8]>, exif: Option<&Exif>) -> Value {
+    let embedded = icc.map(|icc| {
+        let space = icc
+            .get(16..20)
+            .map(|space| String::from_utf8_lossy(space).trim().to_owned());
+        json!({
+            "description": icc_description(icc),
+            "color_space": space,
+            "size": icc.len(),
+        })
+    });
+    // 1 is sRGB, 0xffff anything else
+    let declared = exif
+        .and_then(|exif| exif.number(Tag::ColorSpace))
+        .map(|space| if space == 1.0 { "sRGB" } else { "uncalibrated" });
+
+    json!({
+        "icc": embedded,
+        "exif": declared,
+    })
+}
+
+fn metadata(bytes: &[u8]) -> Result<Value, ServerError> {
+    let format = sniff(bytes)?;
+    let unreadable = |_| ServerError::UnprocessableEntity("not a readable image".to_owned());
+
+    // Only the headers are read, the pixels are never decoded
+    let mut decoder = ImageRader::with_format(Cursor::new(bytes), format)
+        .into_decoder()
+        .map_err(unreadable)?;
+    let (width, height) = decoder.dimensions();
+    let icc = decoder.icc_profile().ok().flatten();
+
+    let exif = Exif::read(bytes);
+    let camera = exif.as_ref().map(|exif| {
+        json!({
+            "make": exif.text(Tag::Make),
+            "model": exif.text(Tag::Model),
+            "lens_make": exif.text(Tag::LensMake),
+            "lens_model": exif.text(Tag::LensModel),
+            "software": exif.text(Tag::Software),
+        })
+    });
+    let settings = exif.as_ref().map(|exif| {
+        json!({
+            "exposure_time": exif.number(Tag::ExposureTime),
+            "f_number": exif.number(Tag::FNumber),
+            "iso": exif.number(Tag::PhotographicSensitivity),
+            "focal_length": exif.number(Tag::FocalLength),
+        })
+    });
+
+    Ok(json!({
+        "format": format.extensions_str().first(),
+        "mime": format.to_mime_type(),
+        "width": width,
+        "height": height,
+        "exif": exif.is_some(),
+        "camera": camera,
+        "settings": settings,
+        "gps": exif.as_ref().and_then(gps),
+        "orientation": orientation(exif.as_ref()),
+        "created": exif.as_ref().and_then(created),
+        "color_profile": color_profile(icc.as_deref(), exif.as_ref()),
+    }))
+}
+
+#[post("/11/metadata")]
+async fn image_metadata(MultipartForm(form): MultipartForm<UploadForm>) -> EndpointRet {
+    let metadata = with_upload_bytes(form.image, move |bytes| metadata(&bytes)).await?;
+
+    Ok(HttpResponse::Ok().json(metadata))
+}
+
+/// Turns the pixels the way the EXIF orientation says they should be shown
+fn orient(img: DynamicImage, orientation: Option<u8>) -> DynamicImage {
+    match orientation {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// The encoders write pixels and nothing else, so whatever metadata the
+/// original had is gone
+fn strip(bytes: &[u8]) -> Result<(Vec<u8>, ImageFormat), ServerError> {
+    let format = sniff(bytes)?;
+    let cant_write = || {
+        ServerError::UnprocessableEntity(format!(
+            "{} images can't be written back",
+            format.extensions_str().first().unwrap_or(&"these")
+        ))
+    };
+    if !format.writing_enabled() {
+        return Err(cant_write());
+    }
+
+    let exif = Exif::read(bytes);
+    let img = orient(decode(bytes)?, orientation(exif.as_ref()));
+
+    let stripped = encode(img, format).map_err(|_| cant_write())?;
+
+    Ok((stripped, format))
+}
+
+#[post("/11/strip")]
+async fn strip_metadata(MultipartForm(form): MultipartForm<UploadForm>) -> EndpointRet {
+    let (body, format) = with_upload_bytes(form.image, move |bytes| strip(&bytes)).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.to_mime_type())
+        .body(body))
+}
@@ -0,0 +1,133 @@
+//! Perceptual hashes, 64 bits each that barely change when the image is
+//! scaled, recompressed or slightly edited, so near duplicates end up a
+//! short Hamming distance apart
+
+use std::f32::consts::PI;
+
+use image::{imageops::FilterType, DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+
+/// Side of the image the pHash DCT runs on
+const DCT_SIZE: usize = 32;
+/// Side of the low frequency corner of the DCT that's kept
+const KEPT: usize = 8;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub enum Algorithm {
+    /// Brighter than the mean
+    #[serde(rename = "ahash")]
+    Average,
+    /// Brighter than the pixel to the right
+    #[serde(rename = "dhash")]
+    Difference,
+    /// Low frequencies above their median
+    #[default]
+    #[serde(rename = "phash")]
+    Perceptual,
+}
+
+impl Algorithm {
+    /// Where the hash lives in the `assets` table
+    pub fn column(&self) -> &'static str {
+        match self {
+            Algorithm::Average => "ahash",
+            Algorithm::Difference => "dhash",
+            Algorithm::Perceptual => "phash",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Hashes {
+    pub ahash: u64,
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+impl Hashes {
+    pub fn get(&self, algorithm: Algorithm) -> u64 {
+        match algorithm {
+            Algorithm::Average => self.ahash,
+            Algorithm::Difference => self.dhash,
+            Algorithm::Perceptual => self.phash,
+        }
+    }
+}
+
+pub fn hex(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+fn gray(img: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    img.resize_exact(width, height, FilterType::Triangle)
+        .into_luma8()
+}
+
+/// First bit is the most significant one
+fn bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |hash, bit| hash << 1 | bit as u64)
+}
+
+fn ahash(img: &DynamicImage) -> u64 {
+    let small = gray(img, 8, 8);
+    let mean = small.pixels().map(|p| p.0[0] as u32).sum::<u32>() / 64;
+
+    bits(small.pixels().map(|p| p.0[0] as u32 > mean))
+}
+
+fn dhash(img: &DynamicImage) -> u64 {
+    // One column more, so every row has 8 neighbour pairs
+    let small = gray(img, 9, 8);
+
+    bits(
+        (0..8)
+            .flat_map(|y| (0..8).map(move |x| (x, y)))
+            .map(|(x, y)| small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0]),
+    )
+}
+
+/// DCT-II of one row or column, only the first `KEPT` coefficients
+fn dct(values: &[f32]) -> [f32; KEPT] {
+    let n = values.len() as f32;
+    let mut out = [0.0; KEPT];
+
+    for (k, coefficient) in out.iter_mut().enumerate() {
+        *coefficient = values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v * (PI / n * (i as f32 + 0.5) * k as f32).cos())
+            .sum();
+    }
+
+    out
+}
+
+fn phash(img: &DynamicImage) -> u64 {
+    let small = gray(img, DCT_SIZE as u32, DCT_SIZE as u32);
+    let pixels: Vec<f32> = small.pixels().map(|p| p.0[0] as f32).collect();
+
+    // Rows first, then the kept columns of the result
+    let rows: Vec<[f32; KEPT]> = pixels.chunks_exact(DCT_SIZE).map(dct).collect();
+    let mut low = Vec::with_capacity(KEPT * KEPT);
+    for u in 0..KEPT {
+        let column: Vec<f32> = rows.iter().map(|row| row[u]).collect();
+        low.extend(dct(&column));
+    }
+    // `low` is column major now, the bit order doesn't matter as long as
+    // it's always the same
+
+    // The DC term is the average brightness, it would skew the median
+    let mut sorted = low[1..].to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    bits(low.iter().map(|&c| c > median))
+}
+
+pub fn hashes(img: &DynamicImage) -> Hashes {
+    Hashes {
+        ahash: ahash(img),
+        dhash: dhash(img),
+        phash: phash(img),
+    }
+}
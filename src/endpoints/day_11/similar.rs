@@ -0,0 +1,163 @@
+use actix_multipart::form::MultipartForm;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+
+use super::{
+    assets::authorize,
+    perceptual::{self, hex, Algorithm},
+    with_upload, UploadForm,
+};
+use crate::common::{AppState, EndpointRet, ServerError};
+
+const DEFAULT_MAX_DISTANCE: u32 = 10;
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+fn default_max_distance() -> u32 {
+    DEFAULT_MAX_DISTANCE
+}
+
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+#[derive(Deserialize)]
+struct SimilarParams {
+    #[serde(default)]
+    algorithm: Algorithm,
+    /// In differing bits, out of 64
+    #[serde(default = "default_max_distance")]
+    max_distance: u32,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+impl SimilarParams {
+    fn check(&self) -> Result<(), ServerError> {
+        if self.max_distance > 64 {
+            return Err(ServerError::BadRequest(
+                "max_distance can be at most 64".to_owned(),
+            ));
+        }
+        if !(1..=MAX_LIMIT).contains(&self.limit) {
+            return Err(ServerError::BadRequest(format!(
+                "limit must be between 1 and {MAX_LIMIT}"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Similar {
+    name: String,
+    mime: String,
+    width: i32,
+    height: i32,
+    size: i64,
+    distance: i64,
+}
+
+/// Closest first, `exclude` keeps an asset from finding itself
+async fn find_similar(
+    pool: &PgPool,
+    hash: u64,
+    params: &SimilarParams,
+    exclude: &str,
+) -> Result<Vec<Similar>, ServerError> {
+    // The column comes from the enum, never from the request
+    let column = params.algorithm.column();
+    let distance = format!("bit_count(({column} # $1)::bit(64))");
+
+    sqlx::query_as(&format!(
+        "SELECT name, mime, width, height, size, {distance} AS distance FROM assets
+        WHERE {column} IS NOT NULL AND name <> $2 AND {distance} <= $3
+        ORDER BY distance, name LIMIT $4;"
+    ))
+    .bind(hash as i64)
+    .bind(exclude)
+    .bind(params.max_distance as i64)
+    .bind(params.limit as i64)
+    .fetch_all(pool)
+    .await
+    .map_err(|_| ServerError::InternalError)
+}
+
+#[post("/11/hash")]
+async fn hash_image(MultipartForm(form): MultipartForm<UploadForm>) -> EndpointRet {
+    let hashes = with_upload(form.image, |img| Ok(perceptual::hashes(&img))).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "ahash": hex(hashes.ahash),
+        "dhash": hex(hashes.dhash),
+        "phash": hex(hashes.phash),
+    })))
+}
+
+#[post("/11/similar")]
+async fn similar_to_upload(
+    req: HttpRequest,
+    MultipartForm(form): MultipartForm<UploadForm>,
+    params: web::Query<SimilarParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    authorize(&req, &state)?;
+    params.check()?;
+
+    let hash = with_upload(form.image, |img| Ok(perceptual::hashes(&img)))
+        .await?
+        .get(params.algorithm);
+    // Asset names are never empty, so nothing is left out
+    let similar = find_similar(&state.pool, hash, &params, "").await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "algorithm": params.algorithm,
+        "hash": hex(hash),
+        "similar": similar,
+    })))
+}
+
+#[get("/11/assets/{name}/similar")]
+async fn similar_to_asset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<SimilarParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    authorize(&req, &state)?;
+    params.check()?;
+    let name = path.into_inner();
+
+    let column = params.algorithm.column();
+    let hash: Option<Option<i64>> =
+        sqlx::query_scalar(&format!("SELECT {column} FROM assets WHERE name = $1;"))
+            .bind(&name)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|_| ServerError::InternalError)?;
+
+    let hash = match hash {
+        Some(Some(hash)) => hash as u64,
+        Some(None) => {
+            return Err(ServerError::UnprocessableEntity(format!(
+                "{name} has no hashes yet, upload it again to get them"
+            )))
+        }
+        None => {
+            return Err(ServerError::NotFound(format!(
+                "no uploaded asset named {name}"
+            )))
+        }
+    };
+    let similar = find_similar(&state.pool, hash, &params, &name).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "name": name,
+        "algorithm": params.algorithm,
+        "hash": hex(hash),
+        "similar": similar,
+    })))
+}
@@ -0,0 +1,363 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use actix_multipart::form::MultipartForm;
+use actix_web::{
+    get,
+    http::header::{self, Header},
+    post, web, HttpRequest, HttpResponse,
+};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use super::{assets::find_asset, decode, encode, UploadForm};
+use crate::common::{AppState, EndpointRet, ServerError};
+
+/// Derived images, named after a hash of the source and the operations
+const CACHE_DIR: &str = "cache/day_11";
+/// Least recently used images are dropped once the cache grows past this
+const MAX_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_DIMENSION: u32 = 4096;
+const MAX_BLUR: f32 = 50.0;
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "image/png" => Some(OutputFormat::Png),
+            "image/jpeg" => Some(OutputFormat::Jpeg),
+            "image/webp" => Some(OutputFormat::Webp),
+            _ => None,
+        }
+    }
+
+    fn mime(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Webp => ImageFormat::WebP,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    /// The query wins over `Accept`, PNG when neither names a format we have
+    fn negotiate(query: Option<Self>, req: &HttpRequest) -> Self {
+        query
+            .or_else(|| {
+                let accept = header::Accept::parse(req).ok()?;
+                accept
+                    .ranked()
+                    .iter()
+                    .find_map(|mime| Self::from_mime(mime.essence_str()))
+            })
+            .unwrap_or(OutputFormat::Png)
+    }
+}
+
+/// The operations, always applied in the order of the fields
+#[derive(Deserialize)]
+struct TransformParams {
+    /// `x,y,width,height`
+    crop: Option<String>,
+    /// Clockwise, in degrees
+    rotate: Option<u32>,
+    /// `<width>x<height>`, ignoring the aspect ratio
+    resize: Option<String>,
+    /// Largest side, keeping the aspect ratio
+    thumbnail: Option<u32>,
+    /// Gaussian sigma
+    blur: Option<f32>,
+    #[serde(default)]
+    grayscale: bool,
+    format: Option<OutputFormat>,
+}
+
+fn numbers<const N: usize>(
+    value: &str,
+    separator: char,
+    name: &str,
+) -> Result<[u32; N], ServerError> {
+    let invalid = || ServerError::BadRequest(format!("invalid {name}: {value}"));
+
+    let parts: Vec<u32> = value
+        .split(separator)
+        .map(|part| part.trim().parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+
+    parts.try_into().map_err(|_| invalid())
+}
+
+fn dimensions(width: u32, height: u32, name: &str) -> Result<(u32, u32), ServerError> {
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(ServerError::BadRequest(format!(
+            "{name} must be between 1 and {MAX_DIMENSION} pixels on each side"
+        )));
+    }
+
+    Ok((width, height))
+}
+
+/// Checked operations, what the cache key is made of
+#[derive(Serialize)]
+struct Transform {
+    crop: Option<[u32; 4]>,
+    rotate: Option<u32>,
+    resize: Option<(u32, u32)>,
+    thumbnail: Option<u32>,
+    blur: Option<f32>,
+    grayscale: bool,
+    format: OutputFormat,
+    /// The format came from `Accept` rather than the query
+    #[serde(skip)]
+    negotiated: bool,
+}
+
+impl TransformParams {
+    fn transform(&self, req: &HttpRequest) -> Result<Transform, ServerError> {
+        let crop = self
+            .crop
+            .as_deref()
+            .map(|crop| numbers::<4>(crop, ',', "crop"))
+            .transpose()?;
+        if let Some([_, _, width, height]) = crop {
+            dimensions(width, height, "crop")?;
+        }
+
+        if self
+            .rotate
+            .is_some_and(|deg| ![90, 180, 270].contains(&deg))
+        {
+            return Err(ServerError::BadRequest(
+                "rotate must be 90, 180 or 270".to_owned(),
+            ));
+        }
+
+        let resize = match self.resize.as_deref() {
+            Some(resize) => {
+                let [width, height] = numbers::<2>(resize, 'x', "resize")?;
+                Some(dimensions(width, height, "resize")?)
+            }
+            None => None,
+        };
+        if let Some(size) = self.thumbnail {
+            dimensions(size, size, "thumbnail")?;
+        }
+
+        if self
+            .blur
+            .is_some_and(|sigma| !(sigma > 0.0 && sigma <= MAX_BLUR))
+        {
+            return Err(ServerError::BadRequest(format!(
+                "blur must be more than 0 and at most {MAX_BLUR}"
+            )));
+        }
+
+        Ok(Transform {
+            crop,
+            rotate: self.rotate,
+            resize,
+            thumbnail: self.thumbnail,
+            blur: self.blur,
+            grayscale: self.grayscale,
+            format: OutputFormat::negotiate(self.format, req),
+            negotiated: self.format.is_none(),
+        })
+    }
+}
+
+impl Transform {
+    fn apply(&self, mut img: DynamicImage) -> Result<DynamicImage, ServerError> {
+        if let Some([x, y, width, height]) = self.crop {
+            let fits = x
+                .checked_add(width)
+                .is_some_and(|right| right <= img.width())
+                && y.checked_add(height)
+                    .is_some_and(|bottom| bottom <= img.height());
+            if !fits {
+                return Err(ServerError::UnprocessableEntity(format!(
+                    "crop doesn't fit in the {}x{} image",
+                    img.width(),
+                    img.height()
+                )));
+            }
+
+            img = img.crop_imm(x, y, width, height);
+        }
+
+        img = match self.rotate {
+            Some(90) => img.rotate90(),
+            Some(180) => img.rotate180(),
+            Some(270) => img.rotate270(),
+            _ => img,
+        };
+
+        if let Some((width, height)) = self.resize {
+            img = img.resize_exact(width, height, FilterType::Lanczos3);
+        }
+        if let Some(size) = self.thumbnail {
+            img = img.thumbnail(size, size);
+        }
+        if let Some(sigma) = self.blur {
+            img = img.blur(sigma);
+        }
+        if self.grayscale {
+            img = img.grayscale();
+        }
+
+        Ok(img)
+    }
+
+    fn cache_path(&self, source: &[u8]) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(source);
+        // Field order is fixed, so equal transforms give equal keys
+        hasher.update(serde_json::to_vec(self).unwrap_or_default());
+
+        Path::new(CACHE_DIR).join(format!(
+            "{:x}.{}",
+            hasher.finalize(),
+            self.format.extension()
+        ))
+    }
+
+    /// Returns the derived image and whether it came from the cache
+    fn run(&self, source: &[u8]) -> Result<(Vec<u8>, bool), ServerError> {
+        let path = self.cache_path(source);
+        if let Ok(cached) = std::fs::read(&path) {
+            // A hit counts as a use, eviction goes by modification time
+            let _ = File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok((cached, true));
+        }
+
+        let img = decode(source)?;
+        let derived = encode(self.apply(img)?, self.format.image_format())
+            .map_err(|_| ServerError::InternalError)?;
+
+        // Every writer gets a temporary file of its own, so readers never see
+        // half a file. Failing to cache only costs the next request some time.
+        let cached = std::fs::create_dir_all(CACHE_DIR)
+            .and_then(|_| NamedTempFile::new_in(CACHE_DIR))
+            .and_then(|mut file| file.write_all(&derived).map(|_| file))
+            .and_then(|file| file.persist(&path).map_err(|e| e.error));
+        if cached.is_ok() {
+            evict();
+        }
+
+        Ok((derived, false))
+    }
+}
+
+/// Drops the least recently used images until the cache fits again
+fn evict() {
+    let Ok(dir) = std::fs::read_dir(CACHE_DIR) else {
+        return;
+    };
+
+    // Temporary files start with a dot, they're still being written
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = dir
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                return None;
+            }
+            let meta = entry.metadata().ok().filter(|meta| meta.is_file())?;
+
+            Some((meta.modified().ok()?, meta.len(), entry.path()))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+
+    files.sort();
+    for (_, size, path) in files {
+        if total <= MAX_CACHE_BYTES {
+            break;
+        }
+        if std::fs::remove_file(path).is_ok() {
+            total -= size;
+        }
+    }
+}
+
+/// Runs the transform on the blocking pool, decoding, blurring and encoding
+/// are too slow for the async workers
+async fn respond<F>(transform: Transform, load: F) -> EndpointRet
+where
+    F: FnOnce() -> Result<Vec<u8>, ServerError> + Send + 'static,
+{
+    let (format, negotiated) = (transform.format, transform.negotiated);
+    let (body, hit) = web::block(move || transform.run(&load()?))
+        .await
+        .map_err(|_| ServerError::InternalError)??;
+
+    let mut res = HttpResponse::Ok();
+    res.content_type(format.mime())
+        .insert_header(("X-Cache", if hit { "hit" } else { "miss" }));
+    // Shared caches must not hand this format to clients accepting another
+    if negotiated {
+        res.insert_header((header::VARY, "Accept"));
+    }
+
+    Ok(res.body(body))
+}
+
+#[post("/11/transform")]
+async fn transform_upload(
+    req: HttpRequest,
+    MultipartForm(form): MultipartForm<UploadForm>,
+    params: web::Query<TransformParams>,
+) -> EndpointRet {
+    let transform = params.transform(&req)?;
+    let file = form.image.file;
+
+    respond(transform, move || {
+        std::fs::read(file.path()).map_err(|_| ServerError::InternalError)
+    })
+    .await
+}
+
+#[get("/11/transform/{name}")]
+async fn transform_asset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<TransformParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let transform = params.transform(&req)?;
+    let (file, _) = find_asset(&state.pool, &path).await?;
+
+    respond(transform, move || {
+        std::fs::read(file).map_err(|_| ServerError::InternalError)
+    })
+    .await
+}
@@ -1,8 +1,8 @@
-use actix_web::{get, post, web, HttpResponse};
-use serde::Deserialize;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::common::{AppState, EndpointRet, ServerError};
+use crate::common::{pagination::PageParams, AppState, EndpointRet, ServerError};
 
 #[get("13/sql")]
 async fn test_sql(state: web::Data<AppState>) -> EndpointRet {
@@ -14,7 +14,7 @@ async fn test_sql(state: web::Data<AppState>) -> EndpointRet {
     Ok(HttpResponse::Ok().body(test_run.to_string()))
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, sqlx::FromRow)]
 struct Order {
     // Realistically, all i32 here shoul be usize or other unsigned types
     // but sqlx can bind unsigned
@@ -58,6 +58,36 @@ async fn insert_orders(state: web::Data<AppState>, body: web::Json<Vec<Order>>)
     Ok(HttpResponse::Ok().finish())
 }
 
+#[get("13/orders")]
+async fn list_orders(
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders;")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let page = page.page(total as usize)?;
+    let range = page.range();
+
+    let orders: Vec<Order> = sqlx::query_as(
+        "SELECT id, region_id, gift_name, quantity FROM orders ORDER BY id LIMIT $1 OFFSET $2;",
+    )
+    .bind(range.len() as i64)
+    .bind(range.start as i64)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    Ok(page.respond(&req).json(json!({
+        "orders": orders,
+        "total": page.total,
+        "next_cursor": page.next_cursor(),
+    })))
+}
+
 #[get("13/orders/total")]
 async fn get_total(state: web::Data<AppState>) -> EndpointRet {
     match sqlx::query_scalar::<sqlx::Postgres, i64>("SELECT SUM(quantity) FROM orders;")
@@ -9,7 +9,7 @@ struct TemplateContext {
     content: String,
 }
 
-static TEMPLATE: &'static str = "\
+static TEMPLATE: &str = "\
 <html>
   <head>
     <title>CCH23 Day 14</title>
@@ -25,9 +25,8 @@ async fn render_unsafe(body: web::Json<TemplateContext>) -> EndpointRet {
     let mut tt = TinyTemplate::new();
     tt.set_default_formatter(&tinytemplate::format_unescaped);
 
-    match tt.add_template("unsafe", TEMPLATE) {
-        Err(_) => return Err(ServerError::InternalError),
-        _ => (),
+    if tt.add_template("unsafe", TEMPLATE).is_err() {
+        return Err(ServerError::InternalError);
     }
 
     let rendered = match tt.render("unsafe", &context) {
@@ -45,9 +44,8 @@ async fn render_safe(body: web::Json<TemplateContext>) -> EndpointRet {
     let context = body.into_inner();
     let mut tt = TinyTemplate::new();
 
-    match tt.add_template("safe", TEMPLATE) {
-        Err(_) => return Err(ServerError::InternalError),
-        _ => (),
+    if tt.add_template("safe", TEMPLATE).is_err() {
+        return Err(ServerError::InternalError);
     }
 
     let rendered = match tt.render("safe", &context) {
@@ -38,11 +38,11 @@ async fn password_nice(body: web::Json<Value>) -> EndpointRet {
             consec = true;
         }
 
-        if VOWELS.iter().any(|&x| x == left) {
+        if VOWELS.contains(&left) {
             vowel_cnt += 1;
         }
 
-        if EXCLUDE.iter().any(|&x| x == window) {
+        if EXCLUDE.contains(&window) {
             return Ok(HttpResponse::BadRequest().json(PasswordBody {
                 input: "naughty".to_owned(),
             }));
@@ -77,7 +77,7 @@ async fn password_game(body: web::Json<PasswordBody>) -> EndpointRet {
 
     if !(input.chars().any(|x| x.is_uppercase())
         && input.chars().any(|x| x.is_lowercase())
-        && input.chars().any(|x| x.is_digit(10)))
+        && input.chars().any(|x| x.is_ascii_digit()))
     {
         // TODO refacor this to look like the rest and use the main loop (for speed)
         return Err(ServerError::PasswordError(
@@ -101,7 +101,7 @@ async fn password_game(body: web::Json<PasswordBody>) -> EndpointRet {
     let (mut _x, mut y, mut z) = (None, None, None);
 
     for c in vec {
-        if let Some(_) = c.to_digit(10) {
+        if c.is_ascii_digit() {
             digit_c += 1;
         }
 
@@ -1,11 +1,11 @@
 use actix_web::{post, web, HttpResponse};
-use serde::Deserialize;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::EndpointRet;
+use crate::common::{EndpointRet, ServerError};
 
 // TODO move Speed and Deer into types
-#[derive(PartialEq, PartialOrd, Deserialize, Clone, Copy)]
+#[derive(PartialEq, Deserialize, Clone, Copy)]
 struct Speed(f64);
 
 impl Default for Speed {
@@ -16,9 +16,15 @@ impl Default for Speed {
 
 impl Eq for Speed {}
 
+impl PartialOrd for Speed {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for Speed {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.0.partial_cmp(&other.0).unwrap()
     }
 }
 
@@ -88,4 +94,334 @@ async fn contest(deer: web::Json<Vec<Deer>>) -> EndpointRet {
     });
 
     Ok(HttpResponse::Ok().json(res))
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Terrain {
+    #[default]
+    Flat,
+    Uphill,
+    Downhill,
+    Snow,
+}
+
+impl Terrain {
+    // How much of the deer's top speed survives on this terrain
+    fn pace(&self) -> f64 {
+        match self {
+            Terrain::Flat => 1.0,
+            Terrain::Uphill => 0.6,
+            Terrain::Downhill => 1.25,
+            Terrain::Snow => 0.8,
+        }
+    }
+
+    // Stamina drained per second of running on this terrain
+    fn effort(&self) -> f64 {
+        match self {
+            Terrain::Flat => 1.0,
+            Terrain::Uphill => 2.5,
+            Terrain::Downhill => 0.5,
+            Terrain::Snow => 1.5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct Segment {
+    length: f64,
+    #[serde(default)]
+    terrain: Terrain,
+}
+
+fn default_tick() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct RaceParams {
+    deer: Vec<Deer>,
+    distance: f64,
+    #[serde(default)]
+    course: Vec<Segment>,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default = "default_tick")]
+    tick: f64,
+}
+
+/// Small SplitMix64 generator, so a race with the same seed always
+/// plays out the same way regardless of which `rand` version is around
+struct RaceRng(u64);
+
+impl RaceRng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+const MAX_TICKS: usize = 10_000;
+const MAX_DEER: usize = 100;
+const MAX_SEGMENTS: usize = 100;
+/// Runner positions kept in the timeline, ticks are skipped past that
+const MAX_TIMELINE: usize = 100_000;
+const BOOST_TICKS: u32 = 3;
+const BOOST_PACE: f64 = 1.5;
+const TIRED_PACE: f64 = 0.5;
+
+struct Runner {
+    position: f64,
+    stamina: f64,
+    boost: u32,
+    finished: Option<f64>,
+    splits: Vec<Option<f64>>,
+}
+
+#[derive(Serialize)]
+struct RunnerTick<'a> {
+    name: &'a str,
+    position: f64,
+    stamina: f64,
+    boosted: bool,
+}
+
+#[derive(Serialize)]
+struct Tick<'a> {
+    time: f64,
+    runners: Vec<RunnerTick<'a>>,
+}
+
+/// Finds the terrain under `position`, anything past the end of the
+/// course is treated as flat ground
+fn terrain_at(course: &[Segment], position: f64) -> Terrain {
+    let mut start = 0.0;
+
+    for segment in course {
+        start += segment.length;
+
+        if position < start {
+            return segment.terrain;
+        }
+    }
+
+    Terrain::Flat
+}
+
+/// Simulates a race over `distance` meters with the given course profile
+///
+/// Every tick each deer runs at its speed scaled by the terrain, strength
+/// sets the stamina pool that the terrain drains and once it runs out the
+/// deer slows down. Snow magic gives a chance for a short speed boost, which
+/// is doubled on snow.
+///
+/// > curl -X POST http://localhost:8000/4/race \
+/// >  -H 'Content-Type: application/json' \
+/// >  -d '{"distance": 100, "seed": 42,
+/// >       "course": [{"length": 50, "terrain": "uphill"}, {"length": 50, "terrain": "snow"}],
+/// >       "deer": [{"name": "Dasher", "strength": 5, "speed": 12.5, "snow_magic_power": 30},
+/// >                {"name": "Dancer", "strength": 9, "speed": 10.0, "snow_magic_power": 80}]}'
+///
+#[post("/4/race")]
+async fn race(body: web::Json<RaceParams>) -> EndpointRet {
+    let params = body.into_inner();
+
+    if params.deer.len() > MAX_DEER {
+        return Err(ServerError::BadRequest(format!(
+            "at most {MAX_DEER} deer can race"
+        )));
+    }
+
+    if params.course.len() > MAX_SEGMENTS {
+        return Err(ServerError::BadRequest(format!(
+            "the course can have at most {MAX_SEGMENTS} segments"
+        )));
+    }
+
+    if !params.distance.is_finite() || params.distance <= 0.0 {
+        return Err(ServerError::BadRequest(
+            "distance must be a positive number".to_owned(),
+        ));
+    }
+
+    if !params.tick.is_finite() || params.tick <= 0.0 {
+        return Err(ServerError::BadRequest(
+            "tick must be a positive number".to_owned(),
+        ));
+    }
+
+    if params
+        .course
+        .iter()
+        .any(|s| !s.length.is_finite() || s.length <= 0.0)
+    {
+        return Err(ServerError::BadRequest(
+            "course segments must have a positive length".to_owned(),
+        ));
+    }
+
+    let res = web::block(move || run_race(&params))
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+fn run_race(params: &RaceParams) -> Value {
+    // Split points are the segment boundaries that fall inside the race
+    let mut checkpoints = Vec::new();
+    let mut boundary = 0.0;
+    for segment in params.course.iter() {
+        boundary += segment.length;
+
+        if boundary >= params.distance {
+            break;
+        }
+
+        checkpoints.push(boundary);
+    }
+    checkpoints.push(params.distance);
+
+    let mut rng = RaceRng(params.seed);
+    let mut runners: Vec<Runner> = params
+        .deer
+        .iter()
+        .map(|d| Runner {
+            position: 0.0,
+            stamina: 100.0 + 10.0 * d.strength.max(0) as f64,
+            boost: 0,
+            finished: None,
+            splits: vec![None; checkpoints.len()],
+        })
+        .collect();
+    let mut timeline = Vec::new();
+    let mut time = 0.0;
+    // Only every `stride`th tick is kept when there are many deer
+    let stride = (MAX_TICKS * params.deer.len())
+        .div_ceil(MAX_TIMELINE)
+        .max(1);
+
+    for i in 0..MAX_TICKS {
+        if runners.iter().all(|r| r.finished.is_some()) {
+            break;
+        }
+
+        for (deer, runner) in params.deer.iter().zip(runners.iter_mut()) {
+            if runner.finished.is_some() {
+                continue;
+            }
+
+            let terrain = terrain_at(&params.course, runner.position);
+
+            // The rng is rolled for every running deer in input order,
+            // which keeps the sequence stable for a given seed
+            let magic = deer.snow_magic_power.max(0) as f64;
+            if runner.boost == 0 && rng.next_f64() < 0.25 * magic / (magic + 100.0) {
+                runner.boost = BOOST_TICKS;
+            }
+
+            let mut pace = terrain.pace();
+            if runner.stamina <= 0.0 {
+                pace *= TIRED_PACE;
+            }
+            if runner.boost > 0 {
+                pace *= if terrain == Terrain::Snow {
+                    BOOST_PACE * 2.0
+                } else {
+                    BOOST_PACE
+                };
+                runner.boost -= 1;
+            }
+
+            let step = deer.speed.0.max(0.0) * pace * params.tick;
+            let start = runner.position;
+            runner.position += step;
+            runner.stamina = (runner.stamina - terrain.effort() * params.tick).max(0.0);
+
+            // Interpolate inside the tick so splits aren't rounded to the tick size
+            for (checkpoint, split) in checkpoints.iter().zip(runner.splits.iter_mut()) {
+                if split.is_none() && runner.position >= *checkpoint {
+                    *split = Some(time + (checkpoint - start) / step * params.tick);
+                }
+            }
+
+            if runner.position >= params.distance {
+                runner.position = params.distance;
+                runner.finished = *runner.splits.last().unwrap();
+            }
+        }
+
+        time += params.tick;
+
+        // The last tick is always kept, it shows where everyone ended up
+        let last = i + 1 == MAX_TICKS || runners.iter().all(|r| r.finished.is_some());
+        if (i + 1) % stride != 0 && !last {
+            continue;
+        }
+
+        timeline.push(Tick {
+            time,
+            runners: params
+                .deer
+                .iter()
+                .zip(runners.iter())
+                .map(|(d, r)| RunnerTick {
+                    name: &d.name,
+                    position: r.position,
+                    stamina: r.stamina,
+                    boosted: r.boost > 0,
+                })
+                .collect(),
+        });
+    }
+
+    // Deer that never made it to the finish line are placed last, by distance covered
+    let mut order: Vec<usize> = (0..runners.len()).collect();
+    order.sort_by(|&a, &b| match (runners[a].finished, runners[b].finished) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => runners[b].position.total_cmp(&runners[a].position),
+    });
+
+    let results: Vec<_> = order
+        .iter()
+        .enumerate()
+        .map(|(place, &i)| {
+            json!({
+                "place": place + 1,
+                "name": params.deer[i].name,
+                "time": runners[i].finished,
+                "distance": runners[i].position,
+            })
+        })
+        .collect();
+
+    let splits: Vec<_> = params
+        .deer
+        .iter()
+        .zip(runners.iter())
+        .map(|(d, r)| {
+            json!({
+                "name": d.name,
+                "times": r.splits,
+            })
+        })
+        .collect();
+
+    json!({
+        "seed": params.seed,
+        "distance": params.distance,
+        "checkpoints": checkpoints,
+        "results": results,
+        "splits": splits,
+        "timeline_stride": stride,
+        "timeline": timeline,
+    })
+}
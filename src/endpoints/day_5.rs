@@ -1,39 +1,501 @@
-use actix_web::{post, web, HttpResponse};
+use std::collections::BTreeMap;
 
-use crate::common::EndpointRet;
+use actix_web::{
+    guard::GuardContext,
+    http::header::CONTENT_TYPE,
+    post,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use csv_core::{ReadFieldResult, Reader as CsvReader};
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
 
-#[derive(serde::Deserialize)]
+use crate::common::{pagination::PageParams, EndpointRet, ServerError};
+
+/// More groups than this can only be empty ones
+const MAX_GROUPS: usize = 1024;
+/// Longest NDJSON line or CSV field a streamed body may have
+const MAX_LINE: usize = 64 * 1024;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum GroupMode {
+    /// Consecutive chunks of `split` names
+    Chunks,
+    /// Deals the names out one by one into `groups` groups
+    RoundRobin,
+    /// `groups` consecutive groups whose sizes differ by at most one
+    Balanced,
+    /// Groups the names by `key`
+    Key,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum GroupKey {
+    #[default]
+    FirstLetter,
+    Length,
+}
+
+impl GroupKey {
+    fn of(&self, name: &str) -> String {
+        match self {
+            GroupKey::FirstLetter => name
+                .chars()
+                .next()
+                .map(|c| c.to_uppercase().to_string())
+                .unwrap_or_default(),
+            GroupKey::Length => name.chars().count().to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
 struct NamesListParam {
-    offset: Option<usize>,
-    limit: Option<usize>,
     split: Option<usize>,
+    mode: Option<GroupMode>,
+    groups: Option<usize>,
+    #[serde(default)]
+    key: GroupKey,
+}
+
+fn positive(value: Option<usize>, name: &str) -> Result<usize, ServerError> {
+    match value {
+        Some(0) => Err(ServerError::BadRequest(format!(
+            "{name} must be greater than 0"
+        ))),
+        Some(value) => Ok(value),
+        None => Err(ServerError::BadRequest(format!("{name} is required"))),
+    }
+}
+
+fn group_count(value: Option<usize>) -> Result<usize, ServerError> {
+    let groups = positive(value, "groups")?;
+    if groups > MAX_GROUPS {
+        return Err(ServerError::BadRequest(format!(
+            "groups can be at most {MAX_GROUPS}"
+        )));
+    }
+
+    Ok(groups)
+}
+
+fn round_robin(names: &[String], groups: usize) -> Vec<Vec<&String>> {
+    let mut res = vec![Vec::new(); groups];
+
+    for (i, name) in names.iter().enumerate() {
+        res[i % groups].push(name);
+    }
+
+    res
+}
+
+fn balanced(names: &[String], groups: usize) -> Vec<&[String]> {
+    let size = names.len() / groups;
+    let extra = names.len() % groups;
+    let mut start = 0;
+
+    (0..groups)
+        .map(|i| {
+            // The first `extra` groups take one of the leftover names each
+            let end = start + size + usize::from(i < extra);
+            let group = &names[start..end];
+            start = end;
+
+            group
+        })
+        .collect()
 }
 
 // http://localhost:8000/5?offset=3&limit=5
+// http://localhost:8000/5?mode=round_robin&groups=3
+// http://localhost:8000/5?mode=key&key=first_letter
 #[post("/5")]
 async fn names_list(
+    req: HttpRequest,
+    page: web::Query<PageParams>,
     params: web::Query<NamesListParam>,
     kids: web::Json<Vec<String>>,
 ) -> EndpointRet {
-    let offset = params.offset.unwrap_or_default();
-    let limit = params.limit.unwrap_or(kids.len());
-
-    if let Some(split) = params.split {
-        print!("{:?}", split);
-        Ok(HttpResponse::Ok().json(
-            kids[offset..]
-                .chunks(split)
-                // we limit the amount of CHUNKS, not the amount of results
-                .take(limit)
-                .collect::<Vec<_>>(),
-        ))
-    } else {
-        print!("nothing");
-        Ok(HttpResponse::Ok().json(
-            kids.iter()
-                .skip(offset)
-                .take(limit)
-                .collect::<Vec<&String>>(),
-        ))
-    }
-}
\ No newline at end of file
+    // Keep the old behaviour of `split` switching to chunks on its own
+    let mode = match (params.mode, params.split) {
+        (Some(mode), _) => Some(mode),
+        (None, Some(_)) => Some(GroupMode::Chunks),
+        (None, None) => None,
+    };
+
+    match mode {
+        Some(GroupMode::Chunks) => {
+            let split = positive(params.split, "split")?;
+            // we limit the amount of CHUNKS, not the amount of results
+            let page = page.page_with_stride(kids.len(), split)?;
+
+            Ok(page
+                .respond(&req)
+                .json(kids[page.range()].chunks(split).collect::<Vec<_>>()))
+        }
+        Some(GroupMode::RoundRobin) => {
+            let groups = group_count(params.groups)?;
+            let page = page.page(kids.len())?;
+
+            Ok(page
+                .respond(&req)
+                .json(round_robin(&kids[page.range()], groups)))
+        }
+        Some(GroupMode::Balanced) => {
+            let groups = group_count(params.groups)?;
+            let page = page.page(kids.len())?;
+
+            Ok(page
+                .respond(&req)
+                .json(balanced(&kids[page.range()], groups)))
+        }
+        Some(GroupMode::Key) => {
+            let page = page.page(kids.len())?;
+            let mut res: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+
+            for name in kids[page.range()].iter() {
+                res.entry(params.key.of(name)).or_default().push(name);
+            }
+
+            Ok(page.respond(&req).json(res))
+        }
+        None => {
+            let page = page.page(kids.len())?;
+
+            Ok(page.respond(&req).json(&kids[page.range()]))
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StreamFormat {
+    Ndjson,
+    Csv,
+}
+
+impl StreamFormat {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "application/x-ndjson" => Some(StreamFormat::Ndjson),
+            "text/csv" => Some(StreamFormat::Csv),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Ndjson => "application/x-ndjson",
+            StreamFormat::Csv => "text/csv",
+        }
+    }
+
+    /// Writes one output line, either a single name or a whole chunk
+    fn write(&self, names: &[String], chunked: bool, out: &mut Vec<u8>) {
+        match self {
+            StreamFormat::Ndjson if chunked => {
+                out.extend(serde_json::to_vec(names).unwrap_or_default())
+            }
+            StreamFormat::Ndjson => out.extend(serde_json::to_vec(&names[0]).unwrap_or_default()),
+            StreamFormat::Csv => {
+                for (i, name) in names.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b',');
+                    }
+
+                    if name.contains([',', '"', '\n', '\r']) {
+                        out.push(b'"');
+                        out.extend(name.replace('"', "\"\"").as_bytes());
+                        out.push(b'"');
+                    } else {
+                        out.extend(name.as_bytes());
+                    }
+                }
+            }
+        }
+
+        out.push(b'\n');
+    }
+}
+
+/// Turns the body bytes into names as they come in. NDJSON has one JSON
+/// string per line, CSV takes every non-empty field as a name.
+enum NameDecoder {
+    Ndjson {
+        line: Vec<u8>,
+    },
+    Csv {
+        reader: Box<CsvReader>,
+        field: Vec<u8>,
+        len: usize,
+    },
+}
+
+impl NameDecoder {
+    fn new(format: StreamFormat) -> Self {
+        match format {
+            StreamFormat::Ndjson => NameDecoder::Ndjson { line: Vec::new() },
+            StreamFormat::Csv => NameDecoder::Csv {
+                reader: Box::new(CsvReader::new()),
+                field: vec![0; 64],
+                len: 0,
+            },
+        }
+    }
+
+    /// Feeds the next piece of the body, an empty `input` marks the end of it
+    fn feed(&mut self, input: &[u8], names: &mut Vec<String>) -> Result<(), ServerError> {
+        match self {
+            NameDecoder::Ndjson { line } => {
+                line.extend_from_slice(input);
+
+                // Anything after the last newline waits for the next piece,
+                // unless this was the last one
+                let complete = match line.iter().rposition(|b| *b == b'\n') {
+                    _ if input.is_empty() => line.len(),
+                    Some(pos) => pos + 1,
+                    None => 0,
+                };
+
+                for raw in line[..complete].split(|b| *b == b'\n') {
+                    if raw.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue;
+                    }
+
+                    match serde_json::from_slice::<String>(raw) {
+                        Ok(name) => names.push(name),
+                        Err(e) => {
+                            return Err(ServerError::BadRequest(format!(
+                                "invalid NDJSON line: {e}"
+                            )))
+                        }
+                    }
+                }
+
+                line.drain(..complete);
+
+                if line.len() > MAX_LINE {
+                    return Err(ServerError::PayloadTooLarge(format!(
+                        "NDJSON lines can be at most {MAX_LINE} bytes"
+                    )));
+                }
+            }
+            NameDecoder::Csv { reader, field, len } => {
+                let mut input = input;
+
+                loop {
+                    let (res, nin, nout) = reader.read_field(input, &mut field[*len..]);
+                    input = &input[nin..];
+                    *len += nout;
+
+                    match res {
+                        ReadFieldResult::InputEmpty | ReadFieldResult::End => break,
+                        ReadFieldResult::OutputFull if field.len() >= MAX_LINE => {
+                            return Err(ServerError::PayloadTooLarge(format!(
+                                "CSV fields can be at most {MAX_LINE} bytes"
+                            )))
+                        }
+                        ReadFieldResult::OutputFull => field.resize(field.len() * 2, 0),
+                        ReadFieldResult::Field { .. } => {
+                            let name = match std::str::from_utf8(&field[..*len]) {
+                                Ok(name) => name.trim(),
+                                Err(_) => {
+                                    return Err(ServerError::BadRequest(
+                                        "CSV body is not valid UTF-8".to_owned(),
+                                    ))
+                                }
+                            };
+
+                            if !name.is_empty() {
+                                names.push(name.to_owned());
+                            }
+
+                            *len = 0;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies offset, limit and split to names as they stream past
+struct NameWindow {
+    format: StreamFormat,
+    skip: usize,
+    remaining: Option<usize>,
+    split: Option<usize>,
+    chunk: Vec<String>,
+}
+
+impl NameWindow {
+    fn is_done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    fn emit(&mut self, names: &[String], out: &mut Vec<u8>) {
+        self.format.write(names, self.split.is_some(), out);
+        self.remaining = self.remaining.map(|r| r - 1);
+    }
+
+    fn push(&mut self, names: Vec<String>, out: &mut Vec<u8>) {
+        for name in names {
+            if self.is_done() {
+                return;
+            }
+
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+
+            match self.split {
+                Some(split) => {
+                    self.chunk.push(name);
+
+                    if self.chunk.len() == split {
+                        let chunk = std::mem::take(&mut self.chunk);
+                        self.emit(&chunk, out);
+                    }
+                }
+                None => self.emit(&[name], out),
+            }
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if !self.chunk.is_empty() && !self.is_done() {
+            let chunk = std::mem::take(&mut self.chunk);
+            self.emit(&chunk, out);
+        }
+    }
+}
+
+struct NameStream {
+    payload: web::Payload,
+    decoder: NameDecoder,
+    window: NameWindow,
+    done: bool,
+}
+
+impl NameStream {
+    async fn next_bytes(&mut self) -> Option<Result<Bytes, ServerError>> {
+        let mut out = Vec::new();
+
+        while !self.done && out.is_empty() {
+            let mut names = Vec::new();
+
+            let res = match self.payload.next().await {
+                Some(Ok(bytes)) if bytes.is_empty() => continue,
+                Some(Ok(bytes)) => self.decoder.feed(&bytes, &mut names),
+                Some(Err(_)) => Err(ServerError::BadRequest(
+                    "failed to read request body".to_owned(),
+                )),
+                None => {
+                    self.done = true;
+                    self.decoder.feed(&[], &mut names)
+                }
+            };
+
+            if let Err(e) = res {
+                self.done = true;
+                return Some(Err(e));
+            }
+
+            self.window.push(names, &mut out);
+
+            if self.done {
+                self.window.finish(&mut out);
+            }
+
+            // Stop reading the body once the limit is reached
+            if self.window.is_done() {
+                self.done = true;
+            }
+        }
+
+        if out.is_empty() {
+            None
+        } else {
+            Some(Ok(Bytes::from(out)))
+        }
+    }
+}
+
+fn is_streamed(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(StreamFormat::from_content_type)
+        .is_some()
+}
+
+/// Streaming version of [`names_list`] for bodies that are too big to buffer
+///
+/// > curl -X POST 'http://localhost:8000/5?offset=1&limit=2&split=2' \
+/// >  -H 'Content-Type: text/csv' \
+/// >  --data-binary $'Ava,Ben\nCal,Dee\nEve'
+///
+/// Ben,Cal
+/// Dee,Eve
+///
+/// Only offset, limit and split are supported, since the other grouping
+/// modes need the whole list.
+#[post("/5", guard = "is_streamed")]
+async fn names_stream(
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    params: web::Query<NamesListParam>,
+    payload: web::Payload,
+) -> EndpointRet {
+    let format = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(StreamFormat::from_content_type)
+        .ok_or(ServerError::InternalError)?;
+
+    let split = match params.mode {
+        Some(GroupMode::Chunks) | None => match params.split {
+            Some(_) => Some(positive(params.split, "split")?),
+            None if params.mode.is_some() => {
+                return Err(ServerError::BadRequest("split is required".to_owned()))
+            }
+            None => None,
+        },
+        Some(_) => {
+            return Err(ServerError::BadRequest(
+                "only chunks can be streamed, send a JSON body for other modes".to_owned(),
+            ))
+        }
+    };
+
+    let mut names = NameStream {
+        payload,
+        decoder: NameDecoder::new(format),
+        window: NameWindow {
+            format,
+            skip: page.start()?,
+            remaining: page.limit,
+            split,
+            chunk: Vec::new(),
+        },
+        done: false,
+    };
+
+    // Once the response has started an error can only cut it short, so a
+    // malformed start of the body still gets a proper status
+    let first = names.next_bytes().await.transpose()?;
+    let body = stream::iter(first.map(Ok)).chain(stream::unfold(names, |mut names| async move {
+        names.next_bytes().await.map(|bytes| (bytes, names))
+    }));
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(body))
+}
@@ -1,6 +1,139 @@
-use actix_web::{post, HttpResponse};
-use serde_json::json;
-use crate::common::EndpointRet;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+};
+
+use actix_multipart::Multipart;
+use actix_web::{
+    http::header::{CONTENT_ENCODING, CONTENT_TYPE},
+    post, web, HttpRequest, HttpResponse,
+};
+use aho_corasick::AhoCorasick;
+use flate2::write::GzDecoder;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::{EndpointRet, ServerError};
+
+const MAX_NGRAM: usize = 5;
+const MAX_TOP: usize = 100;
+/// Words and n-grams counted per document before the rarest are dropped
+const MAX_TRACKED: usize = 50_000;
+/// Longer runs of letters are cut, they're no words anyway
+const MAX_WORD_CHARS: usize = 256;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OffsetUnit {
+    #[default]
+    Byte,
+    Char,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct MatchOptions {
+    #[serde(default = "default_true")]
+    case_sensitive: bool,
+    /// Only count matches that aren't glued to other letters or digits
+    #[serde(default)]
+    word_boundaries: bool,
+    #[serde(default = "default_true")]
+    overlapping: bool,
+    #[serde(default)]
+    offsets: OffsetUnit,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            case_sensitive: true,
+            word_boundaries: false,
+            overlapping: true,
+            offsets: OffsetUnit::Byte,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PhraseMatches {
+    phrase: String,
+    count: usize,
+    offsets: Vec<usize>,
+}
+
+fn chars_eq(a: char, b: char, case_sensitive: bool) -> bool {
+    a == b || (!case_sensitive && a.to_lowercase().eq(b.to_lowercase()))
+}
+
+fn is_word_char(c: Option<char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric())
+}
+
+/// Finds every occurrence of `phrase` in `text`
+///
+/// Matching is done char by char, so offsets stay valid in case insensitive
+/// mode even when lowercasing would change the byte length of the text.
+fn find_phrase(text: &str, phrase: &str, options: &MatchOptions) -> Vec<usize> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let needle: Vec<char> = phrase.chars().collect();
+    let mut offsets = Vec::new();
+    let mut i = 0;
+
+    while i + needle.len() <= chars.len() {
+        let found = needle
+            .iter()
+            .zip(chars[i..].iter())
+            .all(|(n, (_, c))| chars_eq(*c, *n, options.case_sensitive));
+
+        let bounded = !options.word_boundaries
+            || (!is_word_char(i.checked_sub(1).map(|j| chars[j].1))
+                && !is_word_char(chars.get(i + needle.len()).map(|(_, c)| *c)));
+
+        if found && bounded {
+            offsets.push(match options.offsets {
+                OffsetUnit::Byte => chars[i].0,
+                OffsetUnit::Char => i,
+            });
+
+            if !options.overlapping {
+                i += needle.len();
+                continue;
+            }
+        }
+
+        i += 1;
+    }
+
+    offsets
+}
+
+fn count_phrases(
+    text: &str,
+    phrases: &[String],
+    options: &MatchOptions,
+) -> Result<Vec<PhraseMatches>, ServerError> {
+    if phrases.iter().any(|p| p.is_empty()) {
+        return Err(ServerError::BadRequest("phrases can't be empty".to_owned()));
+    }
+
+    Ok(phrases
+        .iter()
+        .map(|phrase| {
+            let offsets = find_phrase(text, phrase, options);
+
+            PhraseMatches {
+                phrase: phrase.clone(),
+                count: offsets.len(),
+                offsets,
+            }
+        })
+        .collect())
+}
 
 // TODO figure out how to properly document this
 ///
@@ -14,19 +147,437 @@ use crate::common::EndpointRet;
 ///
 #[post("/6")]
 async fn elf_on_shelf(text: String) -> EndpointRet {
-    let elf_on_a_shelf = b"elf on a shelf";
-    let shelf_count = text.matches("shelf").count();
-    let elf_on_shelf = text
-        .as_bytes()
-        .windows(elf_on_a_shelf.len())
-        .filter(|window| window == elf_on_a_shelf)
-        .count();
+    let phrases = ["elf", "shelf", "elf on a shelf"].map(String::from);
+    let counts: Vec<usize> = count_phrases(&text, &phrases, &MatchOptions::default())?
+        .iter()
+        .map(|m| m.count)
+        .collect();
 
     let res = json!({
-        "elf": text.matches("elf").count(),
-        "elf on a shelf": elf_on_shelf,
-        "shelf with no elf on it": shelf_count - elf_on_shelf
+        "elf": counts[0],
+        "elf on a shelf": counts[2],
+        "shelf with no elf on it": counts[1] - counts[2]
     });
 
     Ok(HttpResponse::Ok().json(res))
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+struct PhraseQuery {
+    text: String,
+    phrases: Vec<String>,
+    #[serde(flatten)]
+    options: MatchOptions,
+}
+
+///
+/// > curl -X POST http://localhost:8000/6/phrases \
+/// >  -H 'Content-Type: application/json' \
+/// >  -d '{"text": "Elf on a shelf, elfish elves", "phrases": ["elf"],
+/// >       "case_sensitive": false, "word_boundaries": true, "offsets": "char"}'
+///
+/// {"phrases":[{"phrase":"elf","count":1,"offsets":[0]}]}
+///
+#[post("/6/phrases")]
+async fn count_phrase_list(body: web::Json<PhraseQuery>) -> EndpointRet {
+    let query = body.into_inner();
+    let phrases = count_phrases(&query.text, &query.phrases, &query.options)?;
+
+    Ok(HttpResponse::Ok().json(json!({ "phrases": phrases })))
+}
+
+fn default_ngram() -> usize {
+    2
+}
+
+fn default_top() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct AnalyzeParams {
+    /// Comma separated, defaults to the elf phrases of `/6`
+    phrases: Option<String>,
+    /// Only ASCII letters are folded when this is off
+    #[serde(default = "default_true")]
+    case_sensitive: bool,
+    #[serde(default = "default_ngram")]
+    n: usize,
+    #[serde(default = "default_top")]
+    top: usize,
+}
+
+/// Undoes gzip on the fly if the upload was compressed
+enum BodyDecoder {
+    Plain,
+    Gzip(Box<GzDecoder<Vec<u8>>>),
+}
+
+impl BodyDecoder {
+    fn new(gzipped: bool) -> Self {
+        if gzipped {
+            BodyDecoder::Gzip(Box::new(GzDecoder::new(Vec::new())))
+        } else {
+            BodyDecoder::Plain
+        }
+    }
+
+    fn decode(&mut self, chunk: &[u8], analyzer: &mut TextAnalyzer) -> Result<(), ServerError> {
+        match self {
+            BodyDecoder::Plain => analyzer.feed(chunk),
+            BodyDecoder::Gzip(decoder) => {
+                decoder
+                    .write_all(chunk)
+                    .map_err(|_| ServerError::BadRequest("invalid gzip data".to_owned()))?;
+
+                let decoded = std::mem::take(decoder.get_mut());
+                analyzer.feed(&decoded)
+            }
+        }
+    }
+
+    fn finish(self, analyzer: &mut TextAnalyzer) -> Result<(), ServerError> {
+        if let BodyDecoder::Gzip(decoder) = self {
+            let decoded = decoder
+                .finish()
+                .map_err(|_| ServerError::BadRequest("truncated gzip data".to_owned()))?;
+            analyzer.feed(&decoded)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts words or n-grams, keeping up to twice `MAX_TRACKED` of them. Past
+/// that only the `MAX_TRACKED` most frequent ones are kept, so the counts of
+/// a document with a huge vocabulary are approximate.
+#[derive(Default)]
+struct Counter {
+    counts: HashMap<String, usize>,
+    /// Keys added, a key dropped and seen again counts twice
+    added: usize,
+    pruned: bool,
+}
+
+impl Counter {
+    fn add(&mut self, key: String) {
+        let count = self.counts.entry(key).or_insert_with(|| {
+            self.added += 1;
+            0
+        });
+        *count += 1;
+
+        if self.counts.len() >= 2 * MAX_TRACKED {
+            let mut counts: Vec<_> = self.counts.drain().collect();
+            counts.select_nth_unstable_by(MAX_TRACKED, |(_, a), (_, b)| b.cmp(a));
+            counts.truncate(MAX_TRACKED);
+
+            self.counts = counts.into_iter().collect();
+            self.pruned = true;
+        }
+    }
+
+    /// Sorts by count (ties alphabetically) and keeps the first `top` entries
+    fn top(self, top: usize, key: &str) -> Vec<Value> {
+        let mut counts: Vec<(String, usize)> = self.counts.into_iter().collect();
+        counts.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+
+        counts
+            .into_iter()
+            .take(top)
+            .map(|(value, count)| json!({ key: value, "count": count }))
+            .collect()
+    }
+}
+
+/// Collects statistics over a document that is fed in piece by piece
+///
+/// Only a few bytes of the previous piece are kept around, so phrases and
+/// UTF-8 characters that straddle two pieces are still found. Memory is
+/// bounded by the number of words and n-grams kept, not by the size of the
+/// document.
+struct TextAnalyzer {
+    phrases: Vec<String>,
+    matcher: AhoCorasick,
+    phrase_counts: Vec<usize>,
+    carry: Vec<u8>,
+    max_phrase_len: usize,
+    utf8_tail: Vec<u8>,
+    bytes: usize,
+    lines: usize,
+    last_char: Option<char>,
+    sentences: usize,
+    sentence_has_words: bool,
+    word: String,
+    word_chars: usize,
+    word_count: usize,
+    words: Counter,
+    n: usize,
+    window: VecDeque<String>,
+    ngrams: Counter,
+}
+
+impl TextAnalyzer {
+    fn new(params: &AnalyzeParams) -> Result<Self, ServerError> {
+        let phrases: Vec<String> = match &params.phrases {
+            Some(phrases) => phrases
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect(),
+            None => ["elf", "shelf", "elf on a shelf"]
+                .map(String::from)
+                .to_vec(),
+        };
+
+        if !(1..=MAX_NGRAM).contains(&params.n) {
+            return Err(ServerError::BadRequest(format!(
+                "n must be between 1 and {MAX_NGRAM}"
+            )));
+        }
+        if params.top > MAX_TOP {
+            return Err(ServerError::BadRequest(format!(
+                "top can be at most {MAX_TOP}"
+            )));
+        }
+
+        let matcher = AhoCorasick::builder()
+            .ascii_case_insensitive(!params.case_sensitive)
+            .build(&phrases)
+            .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+
+        Ok(TextAnalyzer {
+            phrase_counts: vec![0; phrases.len()],
+            max_phrase_len: phrases.iter().map(|p| p.len()).max().unwrap_or_default(),
+            phrases,
+            matcher,
+            carry: Vec::new(),
+            utf8_tail: Vec::new(),
+            bytes: 0,
+            lines: 0,
+            last_char: None,
+            sentences: 0,
+            sentence_has_words: false,
+            word: String::new(),
+            word_chars: 0,
+            word_count: 0,
+            words: Counter::default(),
+            n: params.n,
+            window: VecDeque::new(),
+            ngrams: Counter::default(),
+        })
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), ServerError> {
+        self.bytes += chunk.len();
+        self.match_phrases(chunk);
+
+        let mut text = std::mem::take(&mut self.utf8_tail);
+        text.extend_from_slice(chunk);
+
+        // Keep a character that got cut in half for the next piece
+        let valid = match std::str::from_utf8(&text) {
+            Ok(_) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => {
+                return Err(ServerError::BadRequest(
+                    "document is not valid UTF-8".to_owned(),
+                ))
+            }
+        };
+
+        self.utf8_tail = text.split_off(valid);
+        // Can't fail, the prefix was validated above
+        let text = String::from_utf8(text).unwrap_or_default();
+
+        for c in text.chars() {
+            self.push_char(c);
+        }
+
+        Ok(())
+    }
+
+    fn match_phrases(&mut self, chunk: &[u8]) {
+        let mut haystack = std::mem::take(&mut self.carry);
+        let carried = haystack.len();
+        haystack.extend_from_slice(chunk);
+
+        // Matches that end inside the carried bytes were counted last time
+        for m in self.matcher.find_overlapping_iter(&haystack) {
+            if m.end() > carried {
+                self.phrase_counts[m.pattern().as_usize()] += 1;
+            }
+        }
+
+        let keep = self.max_phrase_len.saturating_sub(1).min(haystack.len());
+        self.carry = haystack.split_off(haystack.len() - keep);
+    }
+
+    fn push_char(&mut self, c: char) {
+        if c == '\n' {
+            self.lines += 1;
+        }
+
+        if c.is_alphanumeric() || c == '\'' {
+            if self.word_chars < MAX_WORD_CHARS {
+                self.word.extend(c.to_lowercase());
+                self.word_chars += 1;
+            }
+        } else {
+            self.end_word();
+        }
+
+        if matches!(c, '.' | '!' | '?') && self.sentence_has_words {
+            self.sentences += 1;
+            self.sentence_has_words = false;
+        }
+
+        self.last_char = Some(c);
+    }
+
+    fn end_word(&mut self) {
+        if self.word.is_empty() {
+            return;
+        }
+
+        let word = std::mem::take(&mut self.word);
+        self.word_chars = 0;
+        self.word_count += 1;
+        self.sentence_has_words = true;
+        self.words.add(word.clone());
+
+        self.window.push_back(word);
+        if self.window.len() > self.n {
+            self.window.pop_front();
+        }
+
+        if self.window.len() == self.n {
+            let ngram = Vec::from(self.window.clone()).join(" ");
+            self.ngrams.add(ngram);
+        }
+    }
+
+    fn finish(mut self, top: usize) -> Result<Value, ServerError> {
+        if !self.utf8_tail.is_empty() {
+            return Err(ServerError::BadRequest(
+                "document is not valid UTF-8".to_owned(),
+            ));
+        }
+
+        self.end_word();
+
+        // A last line or sentence without a terminator still counts
+        if self.last_char.is_some_and(|c| c != '\n') {
+            self.lines += 1;
+        }
+
+        if self.sentence_has_words {
+            self.sentences += 1;
+        }
+
+        let phrases: Vec<Value> = self
+            .phrases
+            .iter()
+            .zip(self.phrase_counts.iter())
+            .map(|(phrase, count)| json!({ "phrase": phrase, "count": count }))
+            .collect();
+
+        Ok(json!({
+            "bytes": self.bytes,
+            "lines": self.lines,
+            "sentences": self.sentences,
+            "words": self.word_count,
+            // Exact unless the vocabulary was too large to keep
+            "unique_words": self.words.added,
+            "approximate": self.words.pruned || self.ngrams.pruned,
+            "phrases": phrases,
+            "top_words": self.words.top(top, "word"),
+            "top_ngrams": self.ngrams.top(top, "ngram"),
+        }))
+    }
+}
+
+fn is_gzip(content_type: Option<&str>) -> bool {
+    matches!(
+        content_type
+            .and_then(|c| c.split(';').next())
+            .map(str::trim),
+        Some("application/gzip" | "application/x-gzip")
+    )
+}
+
+/// Streams a (possibly huge) text document through the analyzer
+///
+/// The document can be sent as the raw body, gzipped (through either
+/// `Content-Encoding: gzip` or `Content-Type: application/gzip`) or as
+/// the `file` field of a multipart form, which may itself be gzipped.
+///
+/// > curl -X POST 'http://localhost:8000/6/analyze?phrases=elf,shelf&n=2&top=5' \
+/// >  -F 'file=@story.txt.gz;type=application/gzip'
+///
+#[post("/6/analyze")]
+async fn analyze_text(
+    req: HttpRequest,
+    params: web::Query<AnalyzeParams>,
+    mut payload: web::Payload,
+) -> EndpointRet {
+    let mut analyzer = TextAnalyzer::new(&params)?;
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+
+    if content_type.is_some_and(|c| c.starts_with("multipart/form-data")) {
+        let mut multipart = Multipart::new(req.headers(), payload);
+        let mut found = false;
+
+        while let Some(field) = multipart.next().await {
+            let mut field =
+                field.map_err(|e| ServerError::BadRequest(format!("invalid multipart: {e}")))?;
+
+            if field.name() != Some("file") {
+                continue;
+            }
+
+            let gzipped = is_gzip(field.content_type().map(|m| m.essence_str()))
+                || field
+                    .content_disposition()
+                    .and_then(|cd| cd.get_filename())
+                    .is_some_and(|name| name.ends_with(".gz"));
+            let mut decoder = BodyDecoder::new(gzipped);
+
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk
+                    .map_err(|e| ServerError::BadRequest(format!("invalid multipart: {e}")))?;
+                decoder.decode(&chunk, &mut analyzer)?;
+            }
+
+            decoder.finish(&mut analyzer)?;
+            found = true;
+            break;
+        }
+
+        if !found {
+            return Err(ServerError::BadRequest(
+                "missing multipart field 'file'".to_owned(),
+            ));
+        }
+    } else {
+        let gzipped = is_gzip(content_type)
+            || req
+                .headers()
+                .get(CONTENT_ENCODING)
+                .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"gzip"));
+        let mut decoder = BodyDecoder::new(gzipped);
+
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk
+                .map_err(|_| ServerError::BadRequest("failed to read request body".to_owned()))?;
+            decoder.decode(&chunk, &mut analyzer)?;
+        }
+
+        decoder.finish(&mut analyzer)?;
+    }
+
+    Ok(HttpResponse::Ok().json(analyzer.finish(params.top)?))
+}
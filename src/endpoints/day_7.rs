@@ -1,78 +1,498 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use actix_web::{get, HttpRequest, HttpResponse};
-use base64::{engine::general_purpose, Engine};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    get, post, web, HttpRequest, HttpResponse,
+};
+use base64::{
+    alphabet,
+    engine::{
+        general_purpose::{GeneralPurpose, URL_SAFE_NO_PAD},
+        DecodePaddingMode, GeneralPurposeConfig,
+    },
+    Engine,
+};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use sha2::Sha256;
 
-use crate::common::{EndpointRet, ServerError};
+use crate::common::{AppState, EndpointRet, ServerError};
 
-#[get("/7/decode")]
-async fn decode(req: HttpRequest) -> EndpointRet {
-    let cookie = match req.cookie("recipe") {
-        Some(val) => val,
-        None => return Err(ServerError::InternalError),
-    };
+pub(super) mod pantries;
+mod units;
+
+use units::{Normalized, Quantity};
 
-    let val = cookie.value();
-    let decoded = general_purpose::STANDARD.decode(val).unwrap();
+const DEFAULT_COOKIE: &str = "recipe";
 
-    Ok(HttpResponse::Ok().body(decoded))
+// Padding is optional for both alphabets, clients are not consistent about it
+const LENIENT: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const STANDARD: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, LENIENT);
+const URL_SAFE: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, LENIENT);
+
+#[derive(Deserialize)]
+struct CookieParams {
+    cookie: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
-struct BakingData {
-    recipe: HashMap<String, usize>,
-    pantry: HashMap<String, usize>,
+impl CookieParams {
+    fn name(&self) -> &str {
+        self.cookie.as_deref().unwrap_or(DEFAULT_COOKIE)
+    }
 }
 
-#[get("/7/bake")]
-async fn bake(req: HttpRequest) -> EndpointRet {
-    let cookie = match req.cookie("recipe") {
+type HmacSha256 = Hmac<Sha256>;
+
+fn cookie_mac(key: &[u8], payload: &str) -> HmacSha256 {
+    // HMAC takes keys of any length, so this can't fail
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC key of any size");
+    mac.update(payload.as_bytes());
+
+    mac
+}
+
+/// Cookie value handed out by the server, `<payload>.<signature>`
+/// with both parts URL-safe base64 encoded
+fn sign_cookie(key: &[u8], data: &Value) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(data.to_string());
+    let signature = URL_SAFE_NO_PAD.encode(cookie_mac(key, &payload).finalize().into_bytes());
+
+    format!("{payload}.{signature}")
+}
+
+fn signed_cookie<'a>(name: &'a str, key: &[u8], data: &Value) -> Cookie<'a> {
+    Cookie::build(name, sign_cookie(key, data))
+        .path("/7")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+struct DecodedCookie {
+    bytes: Vec<u8>,
+    /// Set for cookies issued by [`issue_cookie`], after their signature was checked
+    signed: bool,
+}
+
+/// Reads and base64 decodes the cookie, picking the URL-safe alphabet
+/// if the value has any of its characters in it
+///
+/// Signed cookies are told apart by their `.`, which never shows up in
+/// base64. A cookie with its signature cut off is just a plain client
+/// made cookie, so it gets no more trust than those.
+fn decode_cookie(req: &HttpRequest, name: &str, key: &[u8]) -> Result<DecodedCookie, ServerError> {
+    let cookie = match req.cookie(name) {
         Some(val) => val,
-        None => return Err(ServerError::InternalError),
+        None => return Err(ServerError::BadRequest(format!("missing cookie '{name}'"))),
     };
 
-    let val = cookie.value();
-    let decoded = general_purpose::STANDARD.decode(val).unwrap();
+    let val = cookie.value().trim();
+    let (val, signed) = match val.split_once('.') {
+        Some((payload, signature)) => {
+            let signature = URL_SAFE_NO_PAD.decode(signature).unwrap_or_default();
 
-    let baking_data: BakingData = match serde_json::from_slice(&decoded) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("{}", e);
-            return Err(ServerError::InternalError);
+            // verify_slice compares in constant time
+            if cookie_mac(key, payload).verify_slice(&signature).is_err() {
+                return Err(ServerError::Forbidden(format!(
+                    "cookie '{name}' has been tampered with"
+                )));
+            }
+
+            (payload, true)
         }
+        None => (val, false),
     };
 
-    let mut cookie_count: usize = usize::MAX;
+    let engine = if val.contains(['-', '_']) {
+        URL_SAFE
+    } else {
+        STANDARD
+    };
+
+    match engine.decode(val) {
+        Ok(bytes) => Ok(DecodedCookie { bytes, signed }),
+        Err(e) => Err(ServerError::UnprocessableEntity(format!(
+            "cookie '{name}' is not valid base64: {e}"
+        ))),
+    }
+}
+
+#[get("/7/decode")]
+async fn decode(
+    req: HttpRequest,
+    params: web::Query<CookieParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let decoded = decode_cookie(&req, params.name(), &state.cookie_key)?;
+
+    Ok(HttpResponse::Ok().body(decoded.bytes))
+}
+
+#[derive(Deserialize, Serialize)]
+struct BakingData {
+    recipe: HashMap<String, Quantity>,
+    pantry: HashMap<String, Quantity>,
+    /// Grams per millilitre for ingredients missing from the built-in table
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    densities: HashMap<String, f64>,
+}
+
+impl BakingData {
+    /// Bakes as many cookies as the pantry allows, returning how many
+    /// and what is left of the pantry
+    fn bake(&self) -> Result<(usize, HashMap<String, Value>), ServerError> {
+        // Bring every recipe amount to the same unit as its pantry amount first
+        let mut amounts = HashMap::new();
+        for (ingredient, needed) in self.recipe.iter() {
+            let have = self.pantry.get(ingredient);
+            let normalized = Normalized::new(ingredient, needed, have, &self.densities)?;
+
+            amounts.insert(ingredient, normalized);
+        }
+
+        let cookie_count = max_batches(&amounts);
+
+        // Leftovers are reported in whatever unit the pantry used
+        let pantry = self
+            .pantry
+            .iter()
+            .map(|(ingredient, have)| match amounts.get(ingredient) {
+                Some(amount) => (ingredient.clone(), amount.leftover(have, cookie_count)),
+                None => (ingredient.clone(), have.to_value()),
+            })
+            .collect();
+
+        Ok((cookie_count, pantry))
+    }
+}
+
+/// How many times the recipe fits into the pantry
+fn max_batches(amounts: &HashMap<&String, Normalized>) -> usize {
+    let mut count: usize = usize::MAX;
 
     // TODO possibly refactor this with fold left
-    for (ingredient, amount) in baking_data.recipe.iter() {
+    for amount in amounts.values() {
         // Skip 0 amount to save time
         // not really necessary, but good practice
-        if *amount <= 0 {
+        if amount.needed == 0.0 {
             continue;
         }
 
-        cookie_count = Ord::min(
-            cookie_count,
-            baking_data.pantry.get(ingredient).unwrap_or(&0) / amount,
-        )
+        let batches = match amount.whole {
+            Some((needed, have)) => usize::try_from(have / needed).unwrap_or(usize::MAX),
+            // The small nudge keeps float noise from unit conversions out of the floor
+            None => (amount.have / amount.needed + 1e-9).floor() as usize,
+        };
+        count = Ord::min(count, batches)
     }
 
-    let mut pantry = baking_data.pantry;
+    count
+}
 
-    // TODO possibly refactor this with a mapping function or something similar
-    for (ingrdient, amount) in pantry.iter_mut() {
-        if let Some(needed) = baking_data.recipe.get(ingrdient) {
-            *amount -= cookie_count * needed;
+#[get("/7/bake")]
+async fn bake(
+    req: HttpRequest,
+    params: web::Query<CookieParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let name = params.name();
+    let decoded = decode_cookie(&req, name, &state.cookie_key)?;
+
+    // Going through serde_path_to_error lets us point at the broken field
+    let de = &mut serde_json::Deserializer::from_slice(&decoded.bytes);
+    let baking_data: BakingData = match serde_path_to_error::deserialize(de) {
+        Ok(val) => val,
+        Err(e) => {
+            return Err(ServerError::UnprocessableEntity(format!(
+                "cookie '{name}' has invalid JSON at '{}': {}",
+                e.path(),
+                e.inner()
+            )))
         }
-    }
+    };
+
+    let (cookie_count, pantry) = baking_data.bake()?;
 
     let res = json!({
         "cookies": cookie_count,
         "pantry": pantry
     });
 
+    // Signed cookies carry the session, so hand back the depleted pantry
+    if decoded.signed {
+        let data = json!({
+            "recipe": baking_data.recipe,
+            "pantry": pantry,
+            "densities": baking_data.densities,
+        });
+
+        return Ok(HttpResponse::Ok()
+            .cookie(signed_cookie(name, &state.cookie_key, &data))
+            .json(res));
+    }
+
     Ok(HttpResponse::Ok().json(res))
 }
+
+/// Hands out a signed cookie with the recipe and pantry, which `bake`
+/// then keeps up to date so repeated bakes work off the same pantry
+///
+/// > curl -X POST http://localhost:8000/7/cookie -c jar \
+/// >  -H 'Content-Type: application/json' \
+/// >  -d '{"recipe": {"flour": 95}, "pantry": {"flour": 385}}'
+/// > curl http://localhost:8000/7/bake -b jar -c jar
+///
+#[post("/7/cookie")]
+async fn issue_cookie(
+    params: web::Query<CookieParams>,
+    body: web::Json<BakingData>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let data = json!(body.into_inner());
+
+    Ok(HttpResponse::Ok()
+        .cookie(signed_cookie(params.name(), &state.cookie_key, &data))
+        .json(data))
+}
+
+fn default_value() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+struct PlanRecipe {
+    ingredients: HashMap<String, usize>,
+    #[serde(default = "default_value")]
+    value: usize,
+}
+
+#[derive(Deserialize)]
+struct PlanRequest {
+    recipes: BTreeMap<String, PlanRecipe>,
+    pantry: HashMap<String, usize>,
+    #[serde(default)]
+    targets: BTreeMap<String, usize>,
+}
+
+// Upper limit on visited nodes, past it the best plan so far is returned
+const PLAN_BUDGET: usize = 200_000;
+// Every node costs recipes times ingredients and the search recurses once
+// per recipe, so both are kept small
+const MAX_PLAN_RECIPES: usize = 32;
+const MAX_PLAN_INGREDIENTS: usize = 32;
+
+/// Branch and bound search over how many batches of each recipe to bake
+struct Planner {
+    needs: Vec<Vec<usize>>,
+    values: Vec<usize>,
+    current: Vec<usize>,
+    best: Vec<usize>,
+    best_value: usize,
+    nodes: usize,
+}
+
+impl Planner {
+    fn batches(&self, k: usize, pantry: &[usize]) -> usize {
+        self.needs[k]
+            .iter()
+            .zip(pantry.iter())
+            .filter(|(need, _)| **need > 0)
+            .map(|(need, have)| have / need)
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Optimistic value of recipes `k..` with what is left in the pantry.
+    /// Every ingredient limits the value of the recipes that use it to the
+    /// best value per unit it gets in any of them.
+    fn bound(&self, k: usize, pantry: &[usize]) -> usize {
+        let loose: Vec<f64> = (k..self.values.len())
+            .map(|r| self.values[r] as f64 * self.batches(r, pantry) as f64)
+            .collect();
+        let mut bound: f64 = loose.iter().sum();
+
+        for (j, have) in pantry.iter().enumerate() {
+            let mut free = 0.0;
+            let mut per_unit: f64 = 0.0;
+
+            for r in k..self.values.len() {
+                match self.needs[r][j] {
+                    0 => free += loose[r - k],
+                    need => per_unit = per_unit.max(self.values[r] as f64 / need as f64),
+                }
+            }
+
+            bound = bound.min(free + per_unit * *have as f64);
+        }
+
+        (bound + 1e-9).floor() as usize
+    }
+
+    fn search(&mut self, k: usize, pantry: &mut Vec<usize>, value: usize) {
+        self.nodes += 1;
+
+        if value > self.best_value {
+            self.best_value = value;
+            self.best = self.current.clone();
+        }
+
+        if k == self.values.len()
+            || self.nodes > PLAN_BUDGET
+            || value.saturating_add(self.bound(k, pantry)) <= self.best_value
+        {
+            return;
+        }
+
+        // Trying the biggest batch first finds good plans early, which
+        // makes the bound prune a lot more
+        for x in (0..=self.batches(k, pantry)).rev() {
+            for (have, need) in pantry.iter_mut().zip(self.needs[k].iter()) {
+                *have -= x * need;
+            }
+            self.current[k] = x;
+
+            self.search(
+                k + 1,
+                pantry,
+                value.saturating_add(x.saturating_mul(self.values[k])),
+            );
+
+            for (have, need) in pantry.iter_mut().zip(self.needs[k].iter()) {
+                *have += x * need;
+            }
+
+            if self.nodes > PLAN_BUDGET {
+                break;
+            }
+        }
+
+        self.current[k] = 0;
+    }
+}
+
+/// Plans which recipes to bake to get the most total value out of a pantry
+///
+/// > curl -X POST http://localhost:8000/7/plan \
+/// >  -H 'Content-Type: application/json' \
+/// >  -d '{"recipes": {"cookie": {"ingredients": {"flour": 100, "sugar": 50}, "value": 3},
+/// >                   "cake": {"ingredients": {"flour": 300, "eggs": 2}, "value": 10}},
+/// >       "pantry": {"flour": 1000, "sugar": 200, "eggs": 5},
+/// >       "targets": {"cake": 4}}'
+///
+/// Also returns the leftover pantry and a shopping list of what is missing
+/// to bake every recipe the number of times asked for in `targets`.
+#[post("/7/plan")]
+async fn plan(body: web::Json<PlanRequest>) -> EndpointRet {
+    let request = body.into_inner();
+
+    if request.recipes.len() > MAX_PLAN_RECIPES {
+        return Err(ServerError::BadRequest(format!(
+            "at most {MAX_PLAN_RECIPES} recipes can be planned at once"
+        )));
+    }
+
+    if let Some(name) = request
+        .targets
+        .keys()
+        .find(|n| !request.recipes.contains_key(*n))
+    {
+        return Err(ServerError::BadRequest(format!("unknown recipe '{name}'")));
+    }
+
+    // Recipes that need nothing could be baked forever
+    if let Some((name, _)) = request
+        .recipes
+        .iter()
+        .find(|(_, r)| r.ingredients.values().all(|a| *a == 0))
+    {
+        return Err(ServerError::BadRequest(format!(
+            "recipe '{name}' needs at least one ingredient"
+        )));
+    }
+
+    let names: Vec<&String> = request.recipes.keys().collect();
+    let mut ingredients: Vec<&String> = request
+        .recipes
+        .values()
+        .flat_map(|r| r.ingredients.keys())
+        .collect();
+    ingredients.sort();
+    ingredients.dedup();
+
+    if ingredients.len() > MAX_PLAN_INGREDIENTS {
+        return Err(ServerError::BadRequest(format!(
+            "the recipes can use at most {MAX_PLAN_INGREDIENTS} different ingredients"
+        )));
+    }
+
+    let mut pantry: Vec<usize> = ingredients
+        .iter()
+        .map(|i| *request.pantry.get(*i).unwrap_or(&0))
+        .collect();
+
+    let mut planner = Planner {
+        needs: request
+            .recipes
+            .values()
+            .map(|r| {
+                ingredients
+                    .iter()
+                    .map(|i| *r.ingredients.get(*i).unwrap_or(&0))
+                    .collect()
+            })
+            .collect(),
+        values: request.recipes.values().map(|r| r.value).collect(),
+        current: vec![0; names.len()],
+        best: vec![0; names.len()],
+        best_value: 0,
+        nodes: 0,
+    };
+    // Even a capped search takes a while, keep it off the async workers
+    let planner = web::block(move || {
+        planner.search(0, &mut pantry, 0);
+        planner
+    })
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    let batches: BTreeMap<&String, usize> = names
+        .iter()
+        .copied()
+        .zip(planner.best.iter().copied())
+        .collect();
+
+    let mut leftover = request.pantry.clone();
+    for (name, count) in batches.iter() {
+        for (ingredient, amount) in request.recipes[*name].ingredients.iter() {
+            if let Some(have) = leftover.get_mut(ingredient) {
+                *have -= count * amount;
+            }
+        }
+    }
+
+    let mut needed: BTreeMap<&String, usize> = BTreeMap::new();
+    for (name, count) in request.targets.iter() {
+        for (ingredient, amount) in request.recipes[name].ingredients.iter() {
+            *needed.entry(ingredient).or_default() += count.saturating_mul(*amount);
+        }
+    }
+
+    let shopping_list: BTreeMap<&String, usize> = needed
+        .into_iter()
+        .map(|(ingredient, amount)| {
+            let have = *request.pantry.get(ingredient).unwrap_or(&0);
+            (ingredient, amount.saturating_sub(have))
+        })
+        .filter(|(_, missing)| *missing > 0)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "batches": batches,
+        "value": planner.best_value,
+        "optimal": planner.nodes <= PLAN_BUDGET,
+        "pantry": leftover,
+        "shopping_list": shopping_list,
+    })))
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{types::Json, Postgres, Transaction};
+
+use super::{units::Quantity, BakingData};
+use crate::common::{pagination::PageParams, AppState, EndpointRet, ServerError};
+
+type Pantry = HashMap<String, Quantity>;
+
+fn pantry_id(path: web::Path<String>) -> Result<String, ServerError> {
+    let id = path.into_inner();
+
+    // Matches the column size in the migration
+    if id.is_empty() || id.len() > 64 {
+        return Err(ServerError::BadRequest(
+            "pantry ids must be 1 to 64 bytes long".to_owned(),
+        ));
+    }
+
+    Ok(id)
+}
+
+/// Loads a pantry and locks its row until the transaction ends
+async fn lock_pantry(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: &str,
+) -> Result<Option<Pantry>, ServerError> {
+    let contents: Option<Json<Pantry>> =
+        sqlx::query_scalar("SELECT contents FROM pantries WHERE id = $1 FOR UPDATE;")
+            .bind(id)
+            .fetch_optional(transaction.as_mut())
+            .await
+            .map_err(|_| ServerError::InternalError)?;
+
+    Ok(contents.map(|Json(pantry)| pantry))
+}
+
+#[get("/7/pantries/{id}")]
+async fn view_pantry(path: web::Path<String>, state: web::Data<AppState>) -> EndpointRet {
+    let id = pantry_id(path)?;
+
+    let row: Option<(Json<Value>, DateTime<Utc>)> =
+        sqlx::query_as("SELECT contents, updated_at FROM pantries WHERE id = $1;")
+            .bind(&id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|_| ServerError::InternalError)?;
+
+    match row {
+        Some((Json(pantry), updated_at)) => Ok(HttpResponse::Ok().json(json!({
+            "id": id,
+            "pantry": pantry,
+            "updated_at": updated_at,
+        }))),
+        None => Err(ServerError::NotFound(format!("no pantry '{id}'"))),
+    }
+}
+
+#[derive(Deserialize)]
+struct RestockRequest {
+    pantry: Pantry,
+    #[serde(default)]
+    densities: HashMap<String, f64>,
+}
+
+/// Adds ingredients to a stored pantry, creating it if needed. Amounts are
+/// added in the unit already stored for the ingredient.
+///
+/// > curl -X POST http://localhost:8000/7/pantries/santa/restock \
+/// >  -H 'Content-Type: application/json' \
+/// >  -d '{"pantry": {"flour": "2 kg", "eggs": 12}}'
+///
+#[post("/7/pantries/{id}/restock")]
+async fn restock_pantry(
+    path: web::Path<String>,
+    body: web::Json<RestockRequest>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let id = pantry_id(path)?;
+    let request = body.into_inner();
+
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    // A missing row can't be locked, so two first restocks would both start
+    // from an empty pantry. Creating it up front makes the second one wait.
+    sqlx::query("INSERT INTO pantries (id) VALUES ($1) ON CONFLICT (id) DO NOTHING;")
+        .bind(&id)
+        .execute(transaction.as_mut())
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let mut pantry = lock_pantry(&mut transaction, &id)
+        .await?
+        .unwrap_or_default();
+
+    for (ingredient, amount) in request.pantry {
+        let restocked = match pantry.get(&ingredient) {
+            Some(have) => have.add(&amount, &ingredient, &request.densities)?,
+            None => amount,
+        };
+
+        pantry.insert(ingredient, restocked);
+    }
+
+    sqlx::query("UPDATE pantries SET contents = $2, updated_at = now() WHERE id = $1;")
+        .bind(&id)
+        .bind(Json(&pantry))
+        .execute(transaction.as_mut())
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    Ok(HttpResponse::Ok().json(json!({ "id": id, "pantry": pantry })))
+}
+
+#[derive(Deserialize)]
+struct BakeRequest {
+    recipe: Pantry,
+    #[serde(default)]
+    densities: HashMap<String, f64>,
+}
+
+/// Same as `7/bake`, but against a stored pantry. The pantry is updated
+/// and the bake is written to its history.
+#[post("/7/pantries/{id}/bake")]
+async fn bake_pantry(
+    path: web::Path<String>,
+    body: web::Json<BakeRequest>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let id = pantry_id(path)?;
+    let request = body.into_inner();
+
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let pantry = match lock_pantry(&mut transaction, &id).await? {
+        Some(pantry) => pantry,
+        None => return Err(ServerError::NotFound(format!("no pantry '{id}'"))),
+    };
+
+    let baking_data = BakingData {
+        recipe: request.recipe,
+        pantry,
+        densities: request.densities,
+    };
+    let (cookie_count, leftover) = baking_data.bake()?;
+
+    sqlx::query("UPDATE pantries SET contents = $2, updated_at = now() WHERE id = $1;")
+        .bind(&id)
+        .bind(Json(&leftover))
+        .execute(transaction.as_mut())
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    sqlx::query(
+        "INSERT INTO bake_history (pantry_id, recipe, cookies, pantry_before, pantry_after)
+        VALUES ($1, $2, $3, $4, $5);",
+    )
+    .bind(&id)
+    .bind(Json(&baking_data.recipe))
+    .bind(cookie_count.min(i64::MAX as usize) as i64)
+    .bind(Json(&baking_data.pantry))
+    .bind(Json(&leftover))
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "cookies": cookie_count,
+        "pantry": leftover
+    })))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct BakeRecord {
+    id: i32,
+    recipe: Json<Value>,
+    cookies: i64,
+    pantry_before: Json<Value>,
+    pantry_after: Json<Value>,
+    baked_at: DateTime<Utc>,
+}
+
+/// Bakes of a stored pantry, newest first
+#[get("/7/pantries/{id}/history")]
+async fn pantry_history(
+    req: HttpRequest,
+    path: web::Path<String>,
+    page: web::Query<PageParams>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let id = pantry_id(path)?;
+
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bake_history WHERE pantry_id = $1;")
+        .bind(&id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|_| ServerError::InternalError)?;
+
+    let page = page.page(total as usize)?;
+    let range = page.range();
+
+    let history: Vec<BakeRecord> = sqlx::query_as(
+        "SELECT id, recipe, cookies, pantry_before, pantry_after, baked_at FROM bake_history
+        WHERE pantry_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3;",
+    )
+    .bind(&id)
+    .bind(range.len() as i64)
+    .bind(range.start as i64)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| ServerError::InternalError)?;
+
+    Ok(page.respond(&req).json(json!({
+        "history": history,
+        "total": page.total,
+        "next_cursor": page.next_cursor(),
+    })))
+}
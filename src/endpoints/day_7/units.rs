@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+
+use crate::common::ServerError;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Dimension {
+    Mass,
+    Volume,
+    Count,
+}
+
+/// A unit, `factor` converts it to the base unit of its dimension
+/// (grams, millilitres or pieces)
+#[derive(Clone, Copy, Debug)]
+pub struct Unit {
+    symbol: &'static str,
+    aliases: &'static [&'static str],
+    dimension: Dimension,
+    factor: f64,
+}
+
+impl Unit {
+    const fn new(
+        symbol: &'static str,
+        aliases: &'static [&'static str],
+        dimension: Dimension,
+        factor: f64,
+    ) -> Self {
+        Unit {
+            symbol,
+            aliases,
+            dimension,
+            factor,
+        }
+    }
+}
+
+const UNITS: &[Unit] = &[
+    Unit::new("mg", &["milligram", "milligrams"], Dimension::Mass, 0.001),
+    Unit::new("g", &["gram", "grams"], Dimension::Mass, 1.0),
+    Unit::new("kg", &["kilogram", "kilograms"], Dimension::Mass, 1000.0),
+    Unit::new("oz", &["ounce", "ounces"], Dimension::Mass, 28.349_523_125),
+    Unit::new(
+        "lb",
+        &["lbs", "pound", "pounds"],
+        Dimension::Mass,
+        453.592_37,
+    ),
+    Unit::new("ml", &["millilitre", "milliliter"], Dimension::Volume, 1.0),
+    Unit::new("cl", &["centilitre", "centiliter"], Dimension::Volume, 10.0),
+    Unit::new("l", &["litre", "liter"], Dimension::Volume, 1000.0),
+    Unit::new("tsp", &["teaspoon"], Dimension::Volume, 4.928_921_593_75),
+    Unit::new(
+        "tbsp",
+        &["tablespoon"],
+        Dimension::Volume,
+        14.786_764_781_25,
+    ),
+    Unit::new("cup", &["cups"], Dimension::Volume, 236.588_236_5),
+    Unit::new("pc", &["pcs", "piece", "pieces"], Dimension::Count, 1.0),
+];
+
+/// Grams per millilitre, used to turn volumes into masses and back
+const DENSITIES: &[(&str, f64)] = &[
+    ("water", 1.0),
+    ("milk", 1.03),
+    ("cream", 1.01),
+    ("butter", 0.911),
+    ("oil", 0.92),
+    ("honey", 1.42),
+    ("flour", 0.53),
+    ("sugar", 0.85),
+    ("brown sugar", 0.93),
+    ("powdered sugar", 0.56),
+    ("cocoa", 0.42),
+    ("salt", 1.2),
+    ("baking powder", 0.9),
+    ("baking soda", 0.92),
+    ("chocolate chips", 0.72),
+    ("oats", 0.34),
+];
+
+fn parse_unit(symbol: &str) -> Option<Unit> {
+    let symbol = symbol.trim().to_lowercase();
+    // Plurals of the long names work too, "millilitres", "teaspoons"...
+    let singular = symbol.strip_suffix('s').unwrap_or(&symbol);
+
+    UNITS
+        .iter()
+        .find(|unit| {
+            unit.symbol == symbol
+                || unit.aliases.contains(&symbol.as_str())
+                || unit.aliases.contains(&singular)
+        })
+        .copied()
+}
+
+/// How the caller wrote a quantity, so leftovers go back in the same shape
+#[derive(Clone, Copy, Debug)]
+pub enum Form {
+    Text,
+    Object,
+}
+
+/// An ingredient amount, either a bare number like the original challenge
+/// uses or an amount with a unit (`"1.5 kg"` or `{"amount": 1.5, "unit": "kg"}`)
+///
+/// Bare whole numbers stay integers, they only go through `f64` when a unit
+/// conversion is involved, so large counts are exact.
+#[derive(Clone, Debug)]
+pub enum Quantity {
+    Whole(u64),
+    Plain(f64),
+    Measured { amount: f64, unit: Unit, form: Form },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawQuantity {
+    // Tried first, an f64 would round anything above 2^53
+    Whole(u64),
+    Number(f64),
+    Text(String),
+    Object { amount: f64, unit: String },
+}
+
+impl<'de> Deserialize<'de> for Quantity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let (amount, unit, form) = match RawQuantity::deserialize(deserializer)? {
+            RawQuantity::Whole(amount) => return Ok(Quantity::Whole(amount)),
+            RawQuantity::Number(amount) => (amount, None, Form::Text),
+            RawQuantity::Text(text) => {
+                let text = text.trim();
+                let split = text
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                    .unwrap_or(text.len());
+                let amount = text[..split]
+                    .parse()
+                    .map_err(|_| D::Error::custom(format!("invalid amount in '{text}'")))?;
+
+                (amount, Some(text[split..].to_owned()), Form::Text)
+            }
+            RawQuantity::Object { amount, unit } => (amount, Some(unit), Form::Object),
+        };
+
+        if !amount.is_finite() || amount < 0.0 {
+            return Err(D::Error::custom("amounts must be positive numbers"));
+        }
+
+        match unit {
+            None => Ok(Quantity::Plain(amount)),
+            Some(unit) if unit.trim().is_empty() => Ok(Quantity::Plain(amount)),
+            Some(unit) => match parse_unit(&unit) {
+                Some(unit) => Ok(Quantity::Measured { amount, unit, form }),
+                None => Err(D::Error::custom(format!("unknown unit '{}'", unit.trim()))),
+            },
+        }
+    }
+}
+
+impl Quantity {
+    fn unit(&self) -> Option<Unit> {
+        match self {
+            Quantity::Whole(_) | Quantity::Plain(_) => None,
+            Quantity::Measured { unit, .. } => Some(*unit),
+        }
+    }
+
+    /// Amount in the base unit of `target`'s dimension. A bare number is read
+    /// in the unit of the other side, as in `{"flour": 500}` next to `"100 g"`.
+    fn amount_in(
+        &self,
+        target: Option<Unit>,
+        ingredient: &str,
+        densities: &HashMap<String, f64>,
+    ) -> Result<f64, ServerError> {
+        let (amount, unit) = match (self, target) {
+            (Quantity::Whole(amount), None) => return Ok(*amount as f64),
+            (Quantity::Whole(amount), Some(target)) => (*amount as f64, target),
+            (Quantity::Plain(amount), None) => return Ok(*amount),
+            (Quantity::Plain(amount), Some(target)) => (*amount, target),
+            (Quantity::Measured { amount, unit, .. }, _) => (*amount, *unit),
+        };
+        let base = amount * unit.factor;
+
+        let target = match target {
+            Some(target) => target.dimension,
+            None => unit.dimension,
+        };
+
+        match (unit.dimension, target) {
+            (from, to) if from == to => Ok(base),
+            (Dimension::Volume, Dimension::Mass) => Ok(base * density(ingredient, densities)?),
+            (Dimension::Mass, Dimension::Volume) => Ok(base / density(ingredient, densities)?),
+            (from, to) => Err(ServerError::UnprocessableEntity(format!(
+                "can't convert '{ingredient}' from {from:?} to {to:?}"
+            ))),
+        }
+    }
+
+    /// The same kind of quantity holding a base unit amount,
+    /// `target` is the unit a bare number was read in
+    fn rebased(&self, base: f64, target: Option<Unit>) -> Quantity {
+        match self {
+            Quantity::Whole(_) | Quantity::Plain(_) => {
+                Quantity::Plain(base / target.map(|u| u.factor).unwrap_or(1.0))
+            }
+            Quantity::Measured { unit, form, .. } => Quantity::Measured {
+                amount: base / unit.factor,
+                unit: *unit,
+                form: *form,
+            },
+        }
+    }
+
+    /// Adds `other` on top of this quantity. The result keeps this
+    /// quantity's unit, or takes `other`'s if this one is a bare number.
+    pub fn add(
+        &self,
+        other: &Quantity,
+        ingredient: &str,
+        densities: &HashMap<String, f64>,
+    ) -> Result<Quantity, ServerError> {
+        if let (Quantity::Whole(have), Quantity::Whole(more)) = (self, other) {
+            return have.checked_add(*more).map(Quantity::Whole).ok_or_else(|| {
+                ServerError::UnprocessableEntity(format!("too much '{ingredient}' to store"))
+            });
+        }
+
+        let target = self.unit().or(other.unit());
+        let base = self.amount_in(target, ingredient, densities)?
+            + other.amount_in(target, ingredient, densities)?;
+
+        match self {
+            Quantity::Whole(_) | Quantity::Plain(_) => Ok(other.rebased(base, target)),
+            Quantity::Measured { .. } => Ok(self.rebased(base, target)),
+        }
+    }
+
+    /// Writes the quantity the way the caller wrote it
+    pub fn to_value(&self) -> Value {
+        match self {
+            Quantity::Whole(amount) => json!(amount),
+            Quantity::Plain(amount) => number(*amount),
+            Quantity::Measured { amount, unit, form } => {
+                let amount = number(*amount);
+
+                match form {
+                    Form::Text => json!(format!("{amount} {}", unit.symbol)),
+                    Form::Object => json!({ "amount": amount, "unit": unit.symbol }),
+                }
+            }
+        }
+    }
+}
+
+impl Serialize for Quantity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_value().serialize(serializer)
+    }
+}
+
+fn density(ingredient: &str, densities: &HashMap<String, f64>) -> Result<f64, ServerError> {
+    let name = ingredient.trim().to_lowercase();
+
+    densities
+        .get(ingredient)
+        .copied()
+        .or_else(|| DENSITIES.iter().find(|(n, _)| *n == name).map(|(_, d)| *d))
+        .filter(|d| *d > 0.0)
+        .ok_or(ServerError::UnprocessableEntity(format!(
+            "no density known for '{ingredient}', add it to 'densities'"
+        )))
+}
+
+/// Whole numbers are kept as integers so unitless pantries look like before
+fn number(amount: f64) -> Value {
+    let rounded = (amount * 1000.0).round() / 1000.0;
+
+    if rounded.fract() == 0.0 && rounded.abs() < u64::MAX as f64 {
+        json!(rounded as u64)
+    } else {
+        json!(rounded)
+    }
+}
+
+/// Recipe and pantry amounts of an ingredient in the same base unit
+pub struct Normalized {
+    pub needed: f64,
+    pub have: f64,
+    /// The same amounts when both are bare whole numbers
+    pub whole: Option<(u64, u64)>,
+    target: Option<Unit>,
+}
+
+impl Normalized {
+    /// Converts both amounts to the base unit of the pantry's unit, or of the
+    /// recipe's when the pantry has a bare number
+    pub fn new(
+        ingredient: &str,
+        needed: &Quantity,
+        have: Option<&Quantity>,
+        densities: &HashMap<String, f64>,
+    ) -> Result<Self, ServerError> {
+        let target = have.and_then(Quantity::unit).or(needed.unit());
+        let whole = match (needed, have) {
+            (Quantity::Whole(needed), Some(Quantity::Whole(have))) => Some((*needed, *have)),
+            (Quantity::Whole(needed), None) => Some((*needed, 0)),
+            _ => None,
+        };
+
+        Ok(Normalized {
+            whole,
+            needed: needed.amount_in(target, ingredient, densities)?,
+            have: match have {
+                Some(have) => have.amount_in(target, ingredient, densities)?,
+                None => 0.0,
+            },
+            target,
+        })
+    }
+
+    /// What is left of `pantry` after baking `batches` times
+    pub fn leftover(&self, pantry: &Quantity, batches: usize) -> Value {
+        if let Some((needed, have)) = self.whole {
+            return json!(have.saturating_sub((batches as u64).saturating_mul(needed)));
+        }
+
+        let left = (self.have - batches as f64 * self.needed).max(0.0);
+
+        pantry.rebased(left, self.target).to_value()
+    }
+}
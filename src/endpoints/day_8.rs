@@ -1,47 +1,338 @@
-use actix_web::{get, web, HttpResponse};
-use serde::{Deserialize, Serialize};
+use actix_web::{get, post, web, HttpResponse, ResponseError};
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
 
-use crate::common::{EndpointRet, ServerError};
+use crate::common::{
+    pokemon::{PokeData, PokeRef},
+    AppState, EndpointRet, ServerError,
+};
 
-#[derive(Deserialize, Serialize)]
-struct PokeData {
-    weight: i32,
+/// Path segments are either ids or names
+fn pokemon_ref(path: web::Path<String>) -> PokeRef {
+    path.parse().unwrap_or_else(|e| match e {})
 }
 
-async fn get_poke_weight(id: usize) -> Result<i32, ServerError> {
-    match reqwest::get(format!("https://pokeapi.co/api/v2/pokemon/{id}/")).await {
-        Ok(data) => match data.json::<PokeData>().await {
-            Ok(body) => return Ok(body.weight),
-            Err(_) => return Err(ServerError::InternalError), // maybe add a different error here
+#[get("/8/weight/{pokemon}")]
+async fn poke_weigth(path: web::Path<String>, state: web::Data<AppState>) -> EndpointRet {
+    let weight = state.pokemon.pokemon(&pokemon_ref(path)).await?.weight;
+
+    Ok(HttpResponse::Ok().body(((weight as f32) / 10.0/* convert hectograms to kg */).to_string()))
+}
+
+const POUNDS_PER_KG: f64 = 2.204_622_621_848_776;
+
+#[get("/8/pokemon/{pokemon}")]
+async fn poke_details(path: web::Path<String>, state: web::Data<AppState>) -> EndpointRet {
+    let pokemon = state.pokemon.pokemon(&pokemon_ref(path)).await?;
+    // PokeAPI has hectograms and decimetres
+    let kg = pokemon.weight as f64 / 10.0;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": pokemon.id,
+        "name": pokemon.name,
+        "height": pokemon.height as f64 / 10.0,
+        "weight": {
+            "kg": kg,
+            "lb": kg * POUNDS_PER_KG,
         },
-        Err(_) => return Err(ServerError::InternalError),
+        "types": pokemon.types,
+        "stats": pokemon.stats,
+    })))
+}
+
+/// Drag coefficient of a sphere, which is what we pretend every Pokémon is
+const SPHERE_DRAG: f64 = 0.47;
+
+/// Returns the gravity in m/s² and the air density in kg/m³ of `/8/drop`'s
+/// `gravity` parameter, a preset name or a custom acceleration breathing
+/// Earth's air
+fn gravity(param: Option<&str>) -> Result<(f64, f64), ServerError> {
+    match param.map(str::to_ascii_lowercase).as_deref() {
+        // Not quite standard gravity, but what the challenge asks for
+        None | Some("earth") => Ok((9.825, 1.225)),
+        Some("moon") => Ok((1.62, 0.0)),
+        Some("mars") => Ok((3.71, 0.020)),
+        Some(custom) => match custom.parse::<f64>() {
+            Ok(g) if g.is_finite() && g > 0.0 => Ok((g, 1.225)),
+            _ => Err(ServerError::BadRequest(format!(
+                "gravity must be earth, moon, mars or a positive number, not {custom}"
+            ))),
+        },
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DropFormat {
+    /// Just the momentum, like the challenge wants
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize)]
+struct FormatParam {
+    #[serde(default)]
+    format: DropFormat,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct DropParams {
+    /// In metres
+    height: f64,
+    gravity: Option<String>,
+    /// Whether to slow the fall down with air resistance
+    drag: bool,
+    drag_coefficient: Option<f64>,
+    /// In kg/m³, overrides the one of the gravity preset
+    air_density: Option<f64>,
+}
+
+impl Default for DropParams {
+    fn default() -> Self {
+        DropParams {
+            height: 10.0,
+            gravity: None,
+            drag: false,
+            drag_coefficient: None,
+            air_density: None,
+        }
+    }
+}
+
+fn non_negative(value: f64) -> bool {
+    value.is_finite() && value >= 0.0
+}
+
+/// Checked drop parameters, in SI units
+struct DropSettings {
+    height: f64,
+    gravity: f64,
+    drag: bool,
+    drag_coefficient: f64,
+    air_density: f64,
+}
+
+impl DropParams {
+    fn settings(&self) -> Result<DropSettings, ServerError> {
+        if !non_negative(self.height) {
+            return Err(ServerError::BadRequest(
+                "height must be a non-negative number of metres".to_owned(),
+            ));
+        }
+
+        let (gravity, preset_density) = gravity(self.gravity.as_deref())?;
+        let air_density = self.air_density.unwrap_or(preset_density);
+        let drag_coefficient = self.drag_coefficient.unwrap_or(SPHERE_DRAG);
+        if !non_negative(air_density) || !non_negative(drag_coefficient) {
+            return Err(ServerError::BadRequest(
+                "air_density and drag_coefficient can't be negative".to_owned(),
+            ));
+        }
+
+        Ok(DropSettings {
+            height: self.height,
+            gravity,
+            drag: self.drag,
+            drag_coefficient,
+            air_density,
+        })
+    }
+}
+
+struct Impact {
+    /// In kg
+    mass: f64,
+    /// In m²
+    cross_section: f64,
+    /// Drag force over mass and velocity squared
+    k: f64,
+    /// In m/s
+    velocity: f64,
+    /// In s
+    time: f64,
+}
+
+impl Impact {
+    fn momentum(&self) -> f64 {
+        self.mass * self.velocity
+    }
+}
+
+/// Falls `height` metres from rest, with quadratic drag when `k` isn't 0,
+/// and returns the velocity and time at impact
+fn fall(height: f64, g: f64, k: f64) -> (f64, f64) {
+    if k == 0.0 {
+        return (f64::sqrt(2.0 * g * height), f64::sqrt(2.0 * height / g));
     }
+
+    // With v(t) = vt·tanh(g·t/vt), the distance fallen is vt²/g·ln(cosh(g·t/vt)).
+    // acosh(e^x) is rewritten so long falls don't overflow the exponential.
+    let terminal = f64::sqrt(g / k);
+    let x = k * height;
+    let settled = f64::sqrt(-f64::exp_m1(-2.0 * x));
+
+    (terminal * settled, terminal / g * (x + f64::ln_1p(settled)))
 }
 
-#[get("/8/weight/{id}")]
-async fn poke_weigth(path: web::Path<usize>) -> EndpointRet {
-    let id = path.into_inner();
+impl DropSettings {
+    fn drop(&self, pokemon: &PokeData) -> Result<Impact, ServerError> {
+        // Hectograms to kg
+        let mass = pokemon.weight as f64 / 10.0;
+        // A sphere as wide as the Pokémon is tall, decimetres to metres
+        let cross_section = std::f64::consts::PI * (pokemon.height as f64 / 20.0).powi(2);
 
-    match get_poke_weight(id).await {
-        Ok(weight) => Ok(HttpResponse::Ok()
-            .body(((weight as f32) / 10.0/* convert hectograms to kg */).to_string())),
-        Err(_) => Err(ServerError::InternalError),
+        let k = if self.drag {
+            if mass <= 0.0 || cross_section <= 0.0 {
+                return Err(ServerError::UnprocessableEntity(format!(
+                    "air resistance needs the weight and height of pokemon {}",
+                    pokemon.id
+                )));
+            }
+
+            self.air_density * self.drag_coefficient * cross_section / (2.0 * mass)
+        } else {
+            0.0
+        };
+
+        let (velocity, time) = fall(self.height, self.gravity, k);
+        Ok(Impact {
+            mass,
+            cross_section,
+            k,
+            velocity,
+            time,
+        })
     }
 }
 
-#[get("/8/drop/{id}")]
-async fn poke_drop(path: web::Path<usize>) -> EndpointRet {
-    const G: f32 = 9.825;
-    const HEIGHT: f32 = 10.0;
+#[get("/8/drop/{pokemon}")]
+async fn poke_drop(
+    path: web::Path<String>,
+    params: web::Query<DropParams>,
+    format: web::Query<FormatParam>,
+    state: web::Data<AppState>,
+) -> EndpointRet {
+    let settings = params.settings()?;
 
-    let id = path.into_inner();
-    let velocity = f32::sqrt(2.0 * HEIGHT * G); // We calculate
+    let pokemon = state.pokemon.pokemon(&pokemon_ref(path)).await?;
+    let impact = settings.drop(&pokemon)?;
+
+    if format.format == DropFormat::Text {
+        // This used to be worked out in f32, which the plain answer keeps
+        // printing so its digits don't change
+        let momentum = impact.mass as f32 * impact.velocity as f32;
+        return Ok(HttpResponse::Ok().body(momentum.to_string()));
+    }
+
+    let air_resistance = settings.drag.then(|| {
+        json!({
+            "drag_coefficient": settings.drag_coefficient,
+            "cross_section": impact.cross_section,
+            "air_density": settings.air_density,
+            "terminal_velocity": if impact.k > 0.0 {
+                Some(f64::sqrt(settings.gravity / impact.k))
+            } else {
+                None
+            },
+        })
+    });
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": pokemon.id,
+        "name": pokemon.name,
+        "mass": impact.mass,
+        "height": settings.height,
+        "gravity": settings.gravity,
+        "air_resistance": air_resistance,
+        "impact_velocity": impact.velocity,
+        "time_to_impact": impact.time,
+        "momentum": impact.momentum(),
+        "kinetic_energy": 0.5 * impact.mass * impact.velocity * impact.velocity,
+    })))
+}
 
-    match get_poke_weight(id).await {
-        Ok(weight) => Ok(HttpResponse::Ok().body(
-            (((weight as f32) / 10.0) * velocity/* convert hectograms to kg and apply velocity*/)
-                .to_string(),
+const MAX_BATCH: usize = 100;
+const DEFAULT_BATCH_CONCURRENCY: usize = 8;
+const MAX_BATCH_CONCURRENCY: usize = 32;
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    /// Ids or names
+    pokemon: Vec<PokeRef>,
+    /// How the momenta are worked out, like the query of `/8/drop`
+    #[serde(default)]
+    drop: DropParams,
+    /// How many lookups may run at the same time
+    concurrency: Option<usize>,
+}
+
+async fn batch_item(state: &AppState, key: PokeRef, settings: &DropSettings) -> Value {
+    let result = match state.pokemon.pokemon(&key).await {
+        Ok(pokemon) => settings.drop(&pokemon).map(|impact| (pokemon, impact)),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok((pokemon, impact)) => json!({
+            "pokemon": key,
+            "status": 200,
+            "id": pokemon.id,
+            "name": pokemon.name,
+            "weight": impact.mass,
+            "momentum": impact.momentum(),
+        }),
+        Err(e) => json!({
+            "pokemon": key,
+            "status": e.status_code().as_u16(),
+            "error": e.to_string(),
+        }),
+    }
+}
+
+#[post("/8/batch")]
+async fn poke_batch(body: web::Json<BatchRequest>, state: web::Data<AppState>) -> EndpointRet {
+    let request = body.into_inner();
+
+    if request.pokemon.len() > MAX_BATCH {
+        return Err(ServerError::BadRequest(format!(
+            "batches are limited to {MAX_BATCH} pokemon"
+        )));
+    }
+    let concurrency = match request.concurrency {
+        None => DEFAULT_BATCH_CONCURRENCY,
+        Some(n @ 1..=MAX_BATCH_CONCURRENCY) => n,
+        Some(_) => {
+            return Err(ServerError::BadRequest(format!(
+                "concurrency must be between 1 and {MAX_BATCH_CONCURRENCY}"
+            )))
+        }
+    };
+    let settings = request.drop.settings()?;
+
+    // `buffered` keeps the results in the order of the request
+    let results: Vec<Value> = stream::iter(request.pokemon)
+        .map(|key| batch_item(&state, key, &settings))
+        .buffered(concurrency)
+        .collect()
+        .await;
+    let failed = results.iter().filter(|r| r["status"] != 200).count();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "succeeded": results.len() - failed,
+        "failed": failed,
+        "results": results,
+    })))
+}
+
+#[get("/8/cache")]
+async fn cache_stats(state: web::Data<AppState>) -> EndpointRet {
+    match state.pokemon.cache_stats() {
+        Some(stats) => Ok(HttpResponse::Ok().json(stats)),
+        None => Err(ServerError::NotFound(
+            "the Pokémon source isn't cached".to_owned(),
         )),
-        Err(_) => Err(ServerError::InternalError),
     }
-}
\ No newline at end of file
+}
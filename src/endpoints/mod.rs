@@ -1,4 +1,3 @@
-use actix_files::Files;
 use actix_web::{get, web, HttpResponse, Scope};
 
 use crate::common::{EndpointRet, ServerError};
@@ -32,14 +31,40 @@ pub fn routes() -> Scope {
         .service(day_1::cube_bits)
         .service(day_4::strength)
         .service(day_4::contest)
+        .service(day_4::race)
+        .service(day_5::names_stream)
         .service(day_5::names_list)
         .service(day_6::elf_on_shelf)
+        .service(day_6::count_phrase_list)
+        .service(day_6::analyze_text)
         .service(day_7::decode)
         .service(day_7::bake)
+        .service(day_7::issue_cookie)
+        .service(day_7::pantries::view_pantry)
+        .service(day_7::pantries::restock_pantry)
+        .service(day_7::pantries::bake_pantry)
+        .service(day_7::pantries::pantry_history)
+        .service(day_7::plan)
         .service(day_8::poke_weigth)
         .service(day_8::poke_drop)
-        .service(Files::new("/11/assets", "assets"))
+        .service(day_8::poke_details)
+        .service(day_8::poke_batch)
+        .service(day_8::cache_stats)
+        .service(day_11::assets::list_assets)
+        .service(day_11::assets::upload_asset)
+        .service(day_11::assets::delete_asset)
+        .service(day_11::similar::similar_to_asset)
+        .service(day_11::assets::download_asset)
         .service(day_11::red_pixels)
+        .service(day_11::analyze_colors)
+        .service(day_11::batch::analyze_batch)
+        .service(day_11::mask::pixel_mask)
+        .service(day_11::metadata::image_metadata)
+        .service(day_11::metadata::strip_metadata)
+        .service(day_11::similar::hash_image)
+        .service(day_11::similar::similar_to_upload)
+        .service(day_11::transform::transform_upload)
+        .service(day_11::transform::transform_asset)
         .service(day_12::set_time)
         .service(day_12::get_elapsed)
         .service(day_12::parse_ulids)
@@ -47,6 +72,7 @@ pub fn routes() -> Scope {
         .service(day_13::test_sql)
         .service(day_13::reset_orders)
         .service(day_13::insert_orders)
+        .service(day_13::list_orders)
         .service(day_13::get_total)
         .service(day_13::get_popular)
         .service(day_14::render_unsafe)